@@ -0,0 +1,39 @@
+// ========== TEST FIXTURES ==========
+//
+// Shared builders for unit tests. Fixtures start from the same constructors
+// the instructions use, so adding a field to a registry type only touches
+// its constructor. Tests adjust the fields they care about afterwards.
+
+use anchor_lang::prelude::*;
+
+use crate::{AssetInfo, AssetRegistry, MarginMode, Obligation, PairRiskParam};
+
+/// Authority-priced asset with 6 decimals and no mint
+pub fn asset(id: u8, price: u64) -> AssetInfo {
+    AssetInfo::new(id, price, 6, Pubkey::default())
+}
+
+/// Registry holding `assets`, with default parameters and no risk params
+pub fn registry(assets: Vec<AssetInfo>) -> AssetRegistry {
+    AssetRegistry {
+        assets,
+        ..AssetRegistry::new(Pubkey::default())
+    }
+}
+
+/// Uncapped risk param for the pair
+pub fn risk_param(asset_id_a: u8, asset_id_b: u8, risk_level: u8) -> PairRiskParam {
+    PairRiskParam {
+        asset_id_a,
+        asset_id_b,
+        risk_level,
+        open_interest_a: 0,
+        open_interest_b: 0,
+        max_open_interest: 0,
+    }
+}
+
+/// Empty obligation owned by the default key
+pub fn obligation(mode: MarginMode) -> Obligation {
+    Obligation::new(Pubkey::default(), [0u8; 32], mode)
+}
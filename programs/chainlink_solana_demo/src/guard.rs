@@ -0,0 +1,157 @@
+// ========== OPERATION GUARD ==========
+//
+// Every user-facing obligation handler calls `assert_operation_allowed` before
+// touching state, or `assert_not_paused` when it changes settings rather than
+// an asset position, so market and asset policy lives in one place:
+//   - a paused market only accepts repayments
+//   - a deprecated asset accepts withdrawals and repayments, not new positions
+//   - deposits need a collateral-enabled asset, borrows a borrow-enabled one
+
+use anchor_lang::prelude::*;
+
+use crate::{AssetInfo, AssetRegistry, AssetStatus, ErrorCode};
+
+#[derive(Debug, Clone, Copy, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub enum Operation {
+    Deposit,
+    Withdraw,
+    Borrow,
+    Repay,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 4] = [
+        Operation::Deposit,
+        Operation::Withdraw,
+        Operation::Borrow,
+        Operation::Repay,
+    ];
+
+    /// Operations that open or grow exposure to an asset
+    pub fn opens_position(self) -> bool {
+        matches!(self, Operation::Deposit | Operation::Borrow)
+    }
+}
+
+/// Returns the asset when `operation` is allowed on it under the current
+/// market policy.
+pub fn assert_operation_allowed(
+    registry: &AssetRegistry,
+    asset_id: u8,
+    operation: Operation,
+) -> std::result::Result<&AssetInfo, ErrorCode> {
+    let asset = registry
+        .assets
+        .iter()
+        .find(|a| a.id == asset_id)
+        .ok_or(ErrorCode::AssetNotFound)?;
+
+    if registry.paused && operation != Operation::Repay {
+        return Err(ErrorCode::MarketPaused);
+    }
+
+    if asset.status == AssetStatus::Deprecated && operation.opens_position() {
+        return Err(ErrorCode::AssetDeprecated);
+    }
+
+    match operation {
+        Operation::Deposit if !asset.collateral_enabled => Err(ErrorCode::CollateralDisabled),
+        Operation::Borrow if !asset.borrow_enabled => Err(ErrorCode::BorrowDisabled),
+        _ => Ok(asset),
+    }
+}
+
+/// Rejects obligation settings changes, such as the seize order or the
+/// co-signer set, while the market is paused: only repayments go through.
+pub fn assert_not_paused(registry: &AssetRegistry) -> std::result::Result<(), ErrorCode> {
+    if registry.paused {
+        return Err(ErrorCode::MarketPaused);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn registry(paused: bool, status: AssetStatus) -> AssetRegistry {
+        let mut registry = fixtures::registry(vec![AssetInfo {
+            status,
+            ..fixtures::asset(0, 1)
+        }]);
+        registry.paused = paused;
+        registry
+    }
+
+    /// Expected outcome for every (paused, status, operation) combination
+    fn expected(paused: bool, status: AssetStatus, operation: Operation) -> Option<ErrorCode> {
+        match (paused, status, operation) {
+            (true, _, Operation::Repay) => None,
+            (true, _, _) => Some(ErrorCode::MarketPaused),
+            (false, AssetStatus::Deprecated, Operation::Deposit | Operation::Borrow) => {
+                Some(ErrorCode::AssetDeprecated)
+            }
+            (false, _, _) => None,
+        }
+    }
+
+    #[test]
+    fn policy_matrix() {
+        for paused in [false, true] {
+            for status in [AssetStatus::Active, AssetStatus::Deprecated] {
+                let registry = registry(paused, status);
+                for operation in Operation::ALL {
+                    let result = assert_operation_allowed(&registry, 0, operation);
+                    let actual = result.err().map(|e| e as u32);
+                    assert_eq!(
+                        actual,
+                        expected(paused, status, operation).map(|e| e as u32),
+                        "paused={} status={:?} operation={:?}",
+                        paused,
+                        status,
+                        operation
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn disabled_flags_only_block_their_operation() {
+        let mut registry = registry(false, AssetStatus::Active);
+        registry.assets[0].collateral_enabled = false;
+        registry.assets[0].borrow_enabled = false;
+        for operation in Operation::ALL {
+            let actual = assert_operation_allowed(&registry, 0, operation)
+                .err()
+                .map(|e| e as u32);
+            let expected = match operation {
+                Operation::Deposit => Some(ErrorCode::CollateralDisabled as u32),
+                Operation::Borrow => Some(ErrorCode::BorrowDisabled as u32),
+                _ => None,
+            };
+            assert_eq!(actual, expected, "operation={:?}", operation);
+        }
+    }
+
+    #[test]
+    fn settings_changes_stop_while_paused() {
+        assert!(assert_not_paused(&registry(false, AssetStatus::Active)).is_ok());
+        assert!(matches!(
+            assert_not_paused(&registry(true, AssetStatus::Active)),
+            Err(ErrorCode::MarketPaused)
+        ));
+    }
+
+    #[test]
+    fn unknown_asset_is_rejected_for_every_operation() {
+        let registry = registry(false, AssetStatus::Active);
+        for operation in Operation::ALL {
+            assert!(matches!(
+                assert_operation_allowed(&registry, 1, operation),
+                Err(ErrorCode::AssetNotFound)
+            ));
+        }
+    }
+}
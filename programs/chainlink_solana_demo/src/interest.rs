@@ -0,0 +1,200 @@
+// ========== INTEREST ==========
+//
+// Each asset carries a reserve with its total deposits and borrows and a
+// cumulative borrow index. Touching an asset accrues interest since the last
+// touch at a rate set by a kinked utilization curve:
+//   - below optimal utilization the rate climbs from base by up to slope1
+//   - above it the rate climbs by up to slope2 more, up to 100% utilization
+// Borrow positions remember the index they were last settled at, so the
+// amount owed is `amount * current index / entry index`, rounded up.
+// Deposits do not earn interest yet; accrued interest stays in the vault.
+
+use std::convert::TryFrom;
+
+use anchor_lang::prelude::*;
+
+use crate::math::{mul_div, DEBT};
+use crate::ErrorCode;
+
+/// Solana produces roughly two slots per second
+pub const SLOTS_PER_YEAR: u64 = 63_072_000;
+
+/// Borrow index of a reserve that has not accrued any interest
+pub const INDEX_ONE: u128 = 1_000_000_000_000;
+
+const BPS: u64 = 10_000;
+
+/// Annual borrow rates in bps as a function of utilization
+#[derive(Debug, Clone, Copy, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, InitSpace)]
+pub struct RateModel {
+    pub base_rate_bps: u16,
+    /// Added across utilizations up to the optimal one
+    pub slope1_bps: u16,
+    /// Added across utilizations above the optimal one
+    pub slope2_bps: u16,
+    pub optimal_utilization_bps: u16,
+}
+
+impl RateModel {
+    /// Charges no interest
+    pub const ZERO: RateModel = RateModel {
+        base_rate_bps: 0,
+        slope1_bps: 0,
+        slope2_bps: 0,
+        optimal_utilization_bps: BPS as u16,
+    };
+
+    pub fn is_valid(&self) -> bool {
+        self.optimal_utilization_bps > 0 && self.optimal_utilization_bps as u64 <= BPS
+    }
+
+    /// Annual borrow rate at `utilization_bps`
+    pub fn borrow_rate_bps(&self, utilization_bps: u64) -> u64 {
+        let utilization = utilization_bps.min(BPS);
+        let optimal = self.optimal_utilization_bps as u64;
+        let base = self.base_rate_bps as u64;
+        let slope1 = self.slope1_bps as u64;
+
+        if utilization <= optimal {
+            return base + slope1 * utilization / optimal;
+        }
+        // Optimal utilization is below 100% here, so the excess range is non-empty
+        base + slope1 + self.slope2_bps as u64 * (utilization - optimal) / (BPS - optimal)
+    }
+}
+
+#[derive(Debug, Clone, Copy, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, InitSpace)]
+pub struct Reserve {
+    pub total_deposits: u64,
+    /// Outstanding borrows including accrued interest
+    pub total_borrows: u64,
+    /// Growth of one unit of debt since the reserve was created, over `INDEX_ONE`
+    pub borrow_index: u128,
+    pub last_update_slot: u64,
+}
+
+impl Reserve {
+    pub fn new(slot: u64) -> Self {
+        Reserve {
+            total_deposits: 0,
+            total_borrows: 0,
+            borrow_index: INDEX_ONE,
+            last_update_slot: slot,
+        }
+    }
+
+    /// Share of deposits currently borrowed, capped at 100%
+    pub fn utilization_bps(&self) -> u64 {
+        if self.total_deposits == 0 {
+            return if self.total_borrows == 0 { 0 } else { BPS };
+        }
+        mul_div(self.total_borrows, BPS, self.total_deposits, DEBT)
+            .unwrap_or(BPS)
+            .min(BPS)
+    }
+
+    /// Accrues interest from `last_update_slot` to `slot`.
+    pub fn accrue(&mut self, model: &RateModel, slot: u64) -> std::result::Result<(), ErrorCode> {
+        let elapsed = slot.saturating_sub(self.last_update_slot);
+        if elapsed == 0 {
+            return Ok(());
+        }
+        self.last_update_slot = slot;
+
+        let rate_bps = model.borrow_rate_bps(self.utilization_bps());
+        if rate_bps == 0 {
+            return Ok(());
+        }
+
+        let growth = self
+            .borrow_index
+            .checked_mul(rate_bps as u128 * elapsed as u128)
+            .ok_or(ErrorCode::MathOverflow)?
+            / (BPS as u128 * SLOTS_PER_YEAR as u128);
+        let index = self
+            .borrow_index
+            .checked_add(growth)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        self.total_borrows =
+            owed(self.total_borrows, self.borrow_index, index).ok_or(ErrorCode::MathOverflow)?;
+        self.borrow_index = index;
+        Ok(())
+    }
+}
+
+/// Amount owed now on `amount` borrowed at `entry_index`, rounded up. An
+/// entry index of 0 marks a position that was never settled and owes
+/// `amount`.
+pub fn owed(amount: u64, entry_index: u128, current_index: u128) -> Option<u64> {
+    if entry_index == 0 {
+        return Some(amount);
+    }
+    let numerator = (amount as u128).checked_mul(current_index)?;
+    let mut result = numerator / entry_index;
+    if result * entry_index != numerator {
+        result += 1;
+    }
+    u64::try_from(result).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: RateModel = RateModel {
+        base_rate_bps: 200,
+        slope1_bps: 800,
+        slope2_bps: 10_000,
+        optimal_utilization_bps: 8_000,
+    };
+
+    #[test]
+    fn rate_follows_the_kinked_curve() {
+        assert_eq!(MODEL.borrow_rate_bps(0), 200);
+        assert_eq!(MODEL.borrow_rate_bps(4_000), 600);
+        assert_eq!(MODEL.borrow_rate_bps(8_000), 1_000);
+        assert_eq!(MODEL.borrow_rate_bps(9_000), 6_000);
+        assert_eq!(MODEL.borrow_rate_bps(10_000), 11_000);
+        assert_eq!(MODEL.borrow_rate_bps(20_000), 11_000);
+        assert_eq!(RateModel::ZERO.borrow_rate_bps(10_000), 0);
+    }
+
+    #[test]
+    fn a_year_at_the_kink_grows_debt_by_the_rate() {
+        let mut reserve = Reserve {
+            total_deposits: 1_000_000,
+            total_borrows: 800_000,
+            ..Reserve::new(0)
+        };
+        reserve.accrue(&MODEL, SLOTS_PER_YEAR).unwrap();
+
+        assert_eq!(reserve.borrow_index, INDEX_ONE + INDEX_ONE / 10);
+        assert_eq!(reserve.total_borrows, 880_000);
+        assert_eq!(owed(1_000, INDEX_ONE, reserve.borrow_index), Some(1_100));
+        assert_eq!(owed(1_000, 0, reserve.borrow_index), Some(1_000));
+    }
+
+    #[test]
+    fn accrual_is_idempotent_within_a_slot() {
+        let mut reserve = Reserve {
+            total_deposits: 100,
+            total_borrows: 50,
+            ..Reserve::new(10)
+        };
+        reserve.accrue(&MODEL, 10).unwrap();
+        assert_eq!(reserve.borrow_index, INDEX_ONE);
+
+        reserve.accrue(&MODEL, 11).unwrap();
+        let index = reserve.borrow_index;
+        assert!(index > INDEX_ONE);
+        reserve.accrue(&MODEL, 11).unwrap();
+        assert_eq!(reserve.borrow_index, index);
+    }
+
+    #[test]
+    fn owed_rounds_up() {
+        assert_eq!(owed(1, INDEX_ONE, INDEX_ONE + 1), Some(2));
+        assert_eq!(owed(0, INDEX_ONE, 2 * INDEX_ONE), Some(0));
+    }
+}
@@ -1,7 +1,28 @@
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::hashv;
+use anchor_spl::token::Token;
+use anchor_spl::token_interface::{transfer_checked, Mint, TokenAccount, TransferChecked};
 
 use chainlink_solana as chainlink;
 
+#[cfg(test)]
+mod fixtures;
+pub mod guard;
+pub mod interest;
+pub mod math;
+pub mod oracle;
+#[cfg(test)]
+mod scenario;
+#[cfg(test)]
+mod spec;
+pub mod verify;
+
+use guard::{assert_not_paused, assert_operation_allowed, Operation};
+use interest::{owed, RateModel, Reserve};
+use math::{mul_div, COLLATERAL, DEBT};
+use oracle::{AuthorityPrice, ChainlinkFeed, OracleSource, PriceOrigin, PriceResult, PriceSource};
+use verify::{registry_issues, RegistryIssue};
+
 declare_id!("41Np7rprA1XXuJ7k83PMh6e5adpyFkdJ2NPh1sGd72A9");
 
 #[program]
@@ -10,9 +31,27 @@ pub mod chainlink_solana_demo {
 
     pub fn initialize_asset_registry(ctx: Context<InitializeAssetRegistry>) -> Result<()> {
         let registry = &mut ctx.accounts.asset_registry;
-        registry.authority = ctx.accounts.authority.key();
-        registry.assets = Vec::new();
-        registry.risk_params = Vec::new();
+        registry.set_inner(AssetRegistry::new(ctx.accounts.authority.key()));
+
+        let directory = &mut ctx.accounts.obligation_directory;
+        directory.open_obligations = 0;
+        directory.total_created = 0;
+
+        let stats = &mut ctx.accounts.market_stats;
+        stats.deposits = 0;
+        stats.withdrawals = 0;
+        stats.borrows = 0;
+        stats.repays = 0;
+        stats.liquidations = 0;
+
+        // Existing subsystems start enabled; new ones ship dormant
+        let gates = &mut ctx.accounts.feature_gates;
+        gates.active = GateSet {
+            isolated_margin: true,
+            private_obligations: true,
+        };
+        gates.pending = None;
+        gates.ready_slot = 0;
 
         msg!(
             "Asset Registry initialized with authority: {}",
@@ -28,16 +67,21 @@ pub mod chainlink_solana_demo {
         decimals: u8,
     ) -> Result<()> {
         let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
 
         // Check if ID already exists
         if registry.assets.iter().any(|a| a.id == id) {
             return Err(ErrorCode::AssetAlreadyExists.into());
         }
+        if registry.assets.len() >= AssetRegistry::MAX_ASSETS {
+            return Err(ErrorCode::RegistryFull.into());
+        }
 
+        let slot = Clock::get()?.slot;
         registry.assets.push(AssetInfo {
-            id,
-            price,
-            decimals,
+            reserve: Reserve::new(slot),
+            price_slot: slot,
+            ..AssetInfo::new(id, price, decimals, Pubkey::default())
         });
 
         msg!(
@@ -49,526 +93,3651 @@ pub mod chainlink_solana_demo {
         Ok(())
     }
 
+    /// Adds an asset keyed by its mint, assigning the lowest unused id.
+    pub fn add_asset_by_mint(
+        ctx: Context<ManageAssetRegistry>,
+        mint: Pubkey,
+        price: u64,
+        decimals: u8,
+    ) -> Result<u8> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+
+        if registry.assets.iter().any(|a| a.mint == mint) {
+            return Err(ErrorCode::AssetAlreadyExists.into());
+        }
+        if registry.assets.len() >= AssetRegistry::MAX_ASSETS {
+            return Err(ErrorCode::RegistryFull.into());
+        }
+
+        let id = (0..=u8::MAX)
+            .find(|id| !registry.assets.iter().any(|a| a.id == *id))
+            .ok_or(ErrorCode::RegistryFull)?;
+
+        let slot = Clock::get()?.slot;
+        registry.assets.push(AssetInfo {
+            reserve: Reserve::new(slot),
+            price_slot: slot,
+            ..AssetInfo::new(id, price, decimals, mint)
+        });
+
+        msg!(
+            "Added asset: id={}, mint={}, price={}, decimals={}",
+            id,
+            mint,
+            price,
+            decimals
+        );
+        Ok(id)
+    }
+
     pub fn update_asset_price(
         ctx: Context<ManageAssetRegistry>,
         id: u8,
         new_price: u64,
     ) -> Result<()> {
         let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
 
         let asset = registry
             .assets
-            .iter_mut()
+            .iter()
             .find(|a| a.id == id)
             .ok_or(ErrorCode::AssetNotFound)?;
+        let resolved = AuthorityPrice { price: new_price }.resolve_price(
+            asset,
+            &[],
+            &Clock::get()?,
+            registry,
+        )?;
 
-        asset.price = new_price;
-
-        msg!("Updated asset {} price to {}", id, new_price);
-        Ok(())
+        store_price(registry, id, resolved)
     }
 
-    pub fn add_risk_param(
-        ctx: Context<ManageAssetRegistry>,
-        asset_id_a: u8,
-        asset_id_b: u8,
-        risk_level: u8,
+    /// Refreshes a Chainlink-sourced asset price from its feed. Anyone can
+    /// call it: the feed must be the one configured for the asset and the
+    /// program the Chainlink store program.
+    pub fn update_price_from_chainlink(
+        ctx: Context<UpdatePriceFromChainlink>,
+        id: u8,
     ) -> Result<()> {
+        let accounts = [
+            ctx.accounts.chainlink_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+        ];
         let registry = &mut ctx.accounts.asset_registry;
 
-        // Verify both assets exist
-        if !registry.assets.iter().any(|a| a.id == asset_id_a) {
-            return Err(ErrorCode::AssetNotFound.into());
-        }
-        if !registry.assets.iter().any(|a| a.id == asset_id_b) {
-            return Err(ErrorCode::AssetNotFound.into());
-        }
+        let asset = registry
+            .assets
+            .iter()
+            .find(|a| a.id == id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+        let feed =
+            ChainlinkFeed::from_source(&asset.oracle).ok_or(ErrorCode::OracleSourceMismatch)?;
+        let resolved = feed.resolve_price(asset, &accounts, &Clock::get()?, registry)?;
 
-        // Check if pair already exists
-        if registry.risk_params.iter().any(|p| {
-            (p.asset_id_a == asset_id_a && p.asset_id_b == asset_id_b)
-                || (p.asset_id_a == asset_id_b && p.asset_id_b == asset_id_a)
-        }) {
-            return Err(ErrorCode::RiskParamAlreadyExists.into());
-        }
+        msg!(
+            "Chainlink price for asset {}: {} (round slot {})",
+            id,
+            resolved.price,
+            resolved.slot
+        );
+        store_price(registry, id, resolved)
+    }
 
-        registry.risk_params.push(PairRiskParam {
-            asset_id_a,
-            asset_id_b,
-            risk_level,
-        });
+    /// Refreshes an asset price through its fallback chain: the primary
+    /// oracle, then the fallback oracle, then the cached price if it is
+    /// within the asset's price age window. Feed accounts for each Chainlink
+    /// source and the Chainlink program are passed, in any order, as
+    /// remaining accounts. Fails with `MissingOraclePrice` when every source
+    /// fails, so a transaction that bundles it never runs on a stale price.
+    pub fn refresh_price(ctx: Context<RefreshPrice>, id: u8) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+
+        let asset = registry
+            .assets
+            .iter()
+            .find(|a| a.id == id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+        let (resolved, origin) =
+            oracle::resolve_with_fallback(asset, ctx.remaining_accounts, &Clock::get()?, registry)?;
 
         msg!(
-            "Added risk param: assets {}-{}, level={}",
-            asset_id_a,
-            asset_id_b,
-            risk_level
+            "Asset {} price {} from {:?} source (slot {})",
+            id,
+            resolved.price,
+            origin,
+            resolved.slot
         );
-        Ok(())
+        if origin == PriceOrigin::Cached {
+            return Ok(());
+        }
+        store_price(registry, id, resolved)
     }
 
-    // ========== OBLIGATION INSTRUCTIONS ==========
+    pub fn set_oracle_source(
+        ctx: Context<ManageAssetRegistry>,
+        id: u8,
+        oracle: OracleSource,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
 
-    pub fn init_obligation(ctx: Context<InitObligation>) -> Result<()> {
-        let obligation = &mut ctx.accounts.obligation;
-        obligation.owner = ctx.accounts.owner.key();
-        obligation.deposits = Vec::new();
-        obligation.borrows = Vec::new();
+        let asset = registry
+            .assets
+            .iter_mut()
+            .find(|a| a.id == id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+        asset.oracle = oracle;
 
-        msg!("Obligation initialized for owner: {}", obligation.owner);
+        msg!("Asset {} oracle source set to {:?}", id, oracle);
         Ok(())
     }
 
-    pub fn add_deposit(ctx: Context<ModifyObligation>, asset_id: u8, amount: u64) -> Result<()> {
-        let obligation = &mut ctx.accounts.obligation;
-        let registry = &ctx.accounts.asset_registry;
-
-        // Verify asset exists
-        if !registry.assets.iter().any(|a| a.id == asset_id) {
-            return Err(ErrorCode::AssetNotFound.into());
-        }
+    /// Sets the oracle tried when the primary one fails and how old, in
+    /// slots, a price may be before the fallback chain and health checks
+    /// reject it. A zero window disables the cached price fallback and all
+    /// price age checks.
+    pub fn set_price_fallback(
+        ctx: Context<ManageAssetRegistry>,
+        id: u8,
+        fallback_oracle: Option<OracleSource>,
+        max_price_age_slots: u64,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
 
-        // Add or update deposit
-        if let Some(position) = obligation
-            .deposits
+        let asset = registry
+            .assets
             .iter_mut()
-            .find(|p| p.asset_id == asset_id)
-        {
-            position.amount = position
-                .amount
-                .checked_add(amount)
-                .ok_or(ErrorCode::MathOverflow)?;
-        } else {
-            obligation.deposits.push(Position { asset_id, amount });
-        }
+            .find(|a| a.id == id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+        asset.fallback_oracle = fallback_oracle;
+        asset.max_price_age_slots = max_price_age_slots;
+
+        msg!(
+            "Asset {} fallback oracle set to {:?}, max price age {} slots",
+            id,
+            fallback_oracle,
+            max_price_age_slots
+        );
+        Ok(())
+    }
+
+    pub fn set_asset_status(
+        ctx: Context<ManageAssetRegistry>,
+        id: u8,
+        status: AssetStatus,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
 
-        msg!("Added deposit: asset_id={}, amount={}", asset_id, amount);
+        let asset = registry
+            .assets
+            .iter_mut()
+            .find(|a| a.id == id)
+            .ok_or(ErrorCode::AssetNotFound)?;
 
-        // Perform health check
-        perform_health_check(&ctx.accounts.obligation, &ctx.accounts.asset_registry)?;
+        asset.status = status;
 
+        msg!("Asset {} status set to {:?}", id, status);
         Ok(())
     }
 
-    pub fn add_borrow(ctx: Context<ModifyObligation>, asset_id: u8, amount: u64) -> Result<()> {
-        let obligation = &mut ctx.accounts.obligation;
-        let registry = &ctx.accounts.asset_registry;
+    /// Creates the vault that custodies a registered mint.
+    pub fn init_vault(ctx: Context<InitVault>) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
 
-        // Verify asset exists
-        if !registry.assets.iter().any(|a| a.id == asset_id) {
+        let mint = ctx.accounts.mint.key();
+        if !registry.assets.iter().any(|a| a.mint == mint) {
             return Err(ErrorCode::AssetNotFound.into());
         }
 
-        msg!("Adding borrow: asset_id={}, amount={}", asset_id, amount);
         msg!(
-            "Current deposits: {}, borrows: {}",
-            obligation.deposits.len(),
-            obligation.borrows.len()
+            "Vault {} created for mint {}",
+            ctx.accounts.vault.key(),
+            mint
         );
+        Ok(())
+    }
 
-        // Add or update borrow
-        if let Some(position) = obligation
-            .borrows
-            .iter_mut()
-            .find(|p| p.asset_id == asset_id)
-        {
-            position.amount = position
-                .amount
-                .checked_add(amount)
-                .ok_or(ErrorCode::MathOverflow)?;
-        } else {
-            obligation.borrows.push(Position { asset_id, amount });
+    /// Sets the share of a deposit's value that counts toward health, in bps.
+    pub fn set_asset_ltv(ctx: Context<ManageAssetRegistry>, id: u8, ltv_bps: u16) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+
+        if ltv_bps > AssetInfo::FULL_LTV_BPS {
+            return Err(ErrorCode::InvalidLtv.into());
         }
 
-        // Perform health check
-        perform_health_check(&ctx.accounts.obligation, &ctx.accounts.asset_registry)?;
+        let asset = registry
+            .assets
+            .iter_mut()
+            .find(|a| a.id == id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+
+        asset.ltv_bps = ltv_bps;
 
+        msg!("Asset {} LTV set to {} bps", id, ltv_bps);
         Ok(())
     }
 
-    pub fn remove_deposit(ctx: Context<ModifyObligation>, asset_id: u8, amount: u64) -> Result<()> {
-        let obligation = &mut ctx.accounts.obligation;
+    /// Sets the plausible price range for an asset; 0 leaves a side
+    /// unbounded.
+    pub fn set_price_bounds(
+        ctx: Context<ManageAssetRegistry>,
+        id: u8,
+        min_price: u64,
+        max_price: u64,
+    ) -> Result<()> {
+        if max_price != 0 && min_price > max_price {
+            return Err(ErrorCode::InvalidPriceBounds.into());
+        }
+
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+
+        let asset = registry
+            .assets
+            .iter_mut()
+            .find(|a| a.id == id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+        asset.min_price = min_price;
+        asset.max_price = max_price;
 
-        msg!("Removing deposit: asset_id={}, amount={}", asset_id, amount);
         msg!(
-            "Current deposits: {}, borrows: {}",
-            obligation.deposits.len(),
-            obligation.borrows.len()
+            "Asset {} price bounds set to [{}, {}]",
+            id,
+            min_price,
+            max_price
         );
+        Ok(())
+    }
 
-        if amount == 0 {
-            return Ok(());
+    /// Sets the asset's borrow rate curve. Interest accrued so far is
+    /// settled at the old rates first.
+    pub fn set_rate_model(
+        ctx: Context<ManageAssetRegistry>,
+        id: u8,
+        rate_model: RateModel,
+    ) -> Result<()> {
+        if !rate_model.is_valid() {
+            return Err(ErrorCode::InvalidRateModel.into());
         }
 
-        let position = obligation
-            .deposits
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+        accrue_reserve(registry, id, Clock::get()?.slot)?;
+
+        let asset = registry
+            .assets
             .iter_mut()
-            .find(|p| p.asset_id == asset_id)
-            .ok_or(ErrorCode::DepositNotFound)?;
+            .find(|a| a.id == id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+        asset.rate_model = rate_model;
 
-        if position.amount < amount {
-            return Err(ErrorCode::InsufficientDeposit.into());
-        }
+        msg!(
+            "Asset {} rate model: base={} slope1={} slope2={} optimal={} bps",
+            id,
+            rate_model.base_rate_bps,
+            rate_model.slope1_bps,
+            rate_model.slope2_bps,
+            rate_model.optimal_utilization_bps
+        );
+        Ok(())
+    }
 
-        position.amount = position.amount.checked_sub(amount).unwrap();
+    pub fn set_asset_flags(
+        ctx: Context<ManageAssetRegistry>,
+        id: u8,
+        collateral_enabled: bool,
+        borrow_enabled: bool,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
 
-        // Remove if zero
-        if position.amount == 0 {
-            obligation.deposits.retain(|p| p.asset_id != asset_id);
-        }
+        let asset = registry
+            .assets
+            .iter_mut()
+            .find(|a| a.id == id)
+            .ok_or(ErrorCode::AssetNotFound)?;
 
-        // Perform health check
-        perform_health_check(&ctx.accounts.obligation, &ctx.accounts.asset_registry)?;
+        asset.collateral_enabled = collateral_enabled;
+        asset.borrow_enabled = borrow_enabled;
 
+        msg!(
+            "Asset {} flags: collateral_enabled={}, borrow_enabled={}",
+            id,
+            collateral_enabled,
+            borrow_enabled
+        );
         Ok(())
     }
 
-    pub fn remove_borrow(ctx: Context<ModifyObligation>, asset_id: u8, amount: u64) -> Result<()> {
-        let obligation = &mut ctx.accounts.obligation;
-
-        msg!("Removing borrow: asset_id={}, amount={}", asset_id, amount);
-
-        if amount == 0 {
-            return Ok(());
-        }
+    pub fn sync_asset_decimals(ctx: Context<SyncAssetDecimals>, id: u8, force: bool) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+        let mint = &ctx.accounts.mint;
 
-        let position = obligation
-            .borrows
+        let asset = registry
+            .assets
             .iter_mut()
-            .find(|p| p.asset_id == asset_id)
-            .ok_or(ErrorCode::BorrowNotFound)?;
+            .find(|a| a.id == id)
+            .ok_or(ErrorCode::AssetNotFound)?;
 
-        if position.amount < amount {
-            return Err(ErrorCode::InsufficientBorrow.into());
+        if asset.mint == Pubkey::default() || asset.mint != mint.key() {
+            return Err(ErrorCode::MintMismatch.into());
         }
 
-        position.amount = position.amount.checked_sub(amount).unwrap();
-
-        // Remove if zero
-        if position.amount == 0 {
-            obligation.borrows.retain(|p| p.asset_id != asset_id);
+        // Amounts are stored in base units, so only the metadata changes; still
+        // require an explicit force while positions reference the asset
+        if asset.open_positions > 0 && !force {
+            return Err(ErrorCode::AssetHasOpenPositions.into());
         }
 
-        // Perform health check
-        perform_health_check(&ctx.accounts.obligation, &ctx.accounts.asset_registry)?;
+        msg!(
+            "Asset {} decimals: {} -> {} ({} open positions)",
+            id,
+            asset.decimals,
+            mint.decimals,
+            asset.open_positions
+        );
+        asset.decimals = mint.decimals;
 
         Ok(())
     }
 
-    // ========== DEBUG INSTRUCTION ==========
+    pub fn set_market_paused(ctx: Context<ManageAssetRegistry>, paused: bool) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+        registry.paused = paused;
 
-    pub fn debug_read_all_data(ctx: Context<DebugReadData>) -> Result<()> {
-        let registry = &ctx.accounts.asset_registry;
-        let obligation = &ctx.accounts.obligation;
+        msg!("Market paused: {}", paused);
+        Ok(())
+    }
 
-        msg!("=== ASSET REGISTRY DATA ===");
-        msg!("Authority: {}", registry.authority);
-        msg!("Total assets: {}", registry.assets.len());
+    /// Queues a new set of feature gates, applied by `apply_feature_gates`
+    /// once `FeatureGates::TIMELOCK_SLOTS` have passed. Replaces any queued set.
+    pub fn queue_feature_gates(ctx: Context<ManageFeatureGates>, gates: GateSet) -> Result<()> {
+        ctx.accounts.asset_registry.bump_admin_nonce()?;
 
-        for asset in &registry.assets {
-            msg!(
-                "Asset: id={}, price={}, decimals={}",
-                asset.id,
-                asset.price,
-                asset.decimals
-            );
-        }
+        let ready_slot = Clock::get()?
+            .slot
+            .checked_add(FeatureGates::TIMELOCK_SLOTS)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let feature_gates = &mut ctx.accounts.feature_gates;
+        feature_gates.pending = Some(gates.clone());
+        feature_gates.ready_slot = ready_slot;
 
-        msg!("Total risk params: {}", registry.risk_params.len());
-        for param in &registry.risk_params {
-            msg!(
-                "Risk param: {}-{}, level={}",
-                param.asset_id_a,
-                param.asset_id_b,
-                param.risk_level
-            );
-        }
+        msg!(
+            "Feature gates queued: {:?}, ready at slot {}",
+            gates,
+            ready_slot
+        );
+        Ok(())
+    }
 
-        msg!("=== OBLIGATION DATA ===");
-        msg!("Owner: {}", obligation.owner);
-        msg!("Deposits: {}", obligation.deposits.len());
-        for deposit in &obligation.deposits {
-            msg!(
-                "  Deposit: asset_id={}, amount={}",
-                deposit.asset_id,
-                deposit.amount
-            );
-        }
+    pub fn apply_feature_gates(ctx: Context<ManageFeatureGates>) -> Result<()> {
+        ctx.accounts.asset_registry.bump_admin_nonce()?;
 
-        msg!("Borrows: {}", obligation.borrows.len());
-        for borrow in &obligation.borrows {
+        let feature_gates = &mut ctx.accounts.feature_gates;
+        let current_slot = Clock::get()?.slot;
+        if current_slot < feature_gates.ready_slot {
             msg!(
-                "  Borrow: asset_id={}, amount={}",
-                borrow.asset_id,
-                borrow.amount
+                "Feature gates not ready: current_slot={}, ready_slot={}",
+                current_slot,
+                feature_gates.ready_slot
             );
+            return Err(ErrorCode::FeatureGateTimelockActive.into());
         }
+        let gates = feature_gates
+            .pending
+            .take()
+            .ok_or(ErrorCode::NoPendingFeatureGates)?;
 
+        msg!("Feature gates applied: {:?}", gates);
+        feature_gates.active = gates;
         Ok(())
     }
 
-    use chainlink_solana::Round;
+    /// Caps open obligations per owner; 0 disables the cap.
+    pub fn set_max_obligations_per_owner(
+        ctx: Context<ManageAssetRegistry>,
+        max_obligations_per_owner: u8,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+        registry.max_obligations_per_owner = max_obligations_per_owner;
 
-    use super::*;
-    pub fn execute(ctx: Context<Execute>) -> Result<()> {
-        let round: Round = chainlink::latest_round_data(
-            ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
-        )?;
+        msg!(
+            "Max obligations per owner set to {}",
+            max_obligations_per_owner
+        );
+        Ok(())
+    }
 
-        let description: String = chainlink::description(
-            ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
-        )?;
+    pub fn set_withdraw_cooldown(
+        ctx: Context<ManageAssetRegistry>,
+        id: u8,
+        cooldown_slots: u64,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
 
-        let decimals: u8 = chainlink::decimals(
-            ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
-        )?;
+        let asset = registry
+            .assets
+            .iter_mut()
+            .find(|a| a.id == id)
+            .ok_or(ErrorCode::AssetNotFound)?;
 
-        let decimal: &mut Account<Decimal> = &mut ctx.accounts.decimal;
-        decimal.value = round.answer;
-        decimal.decimals = u32::from(decimals);
+        asset.withdraw_cooldown_slots = cooldown_slots;
 
-        let decimal_print: Decimal = Decimal::new(round.answer, u32::from(decimals));
-        msg!("{} price is {}", description, decimal_print);
+        msg!(
+            "Asset {} withdraw cooldown set to {} slots",
+            id,
+            cooldown_slots
+        );
         Ok(())
     }
-}
 
-// ========== HEALTH CHECK FUNCTION ==========
+    /// Caps the open interest value of a pair; 0 disables the cap.
+    pub fn set_open_interest_cap(
+        ctx: Context<ManageAssetRegistry>,
+        asset_id_a: u8,
+        asset_id_b: u8,
+        max_open_interest: u64,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
 
-fn perform_health_check(obligation: &Obligation, registry: &AssetRegistry) -> Result<()> {
-    msg!("=== HEALTH CHECK START ===");
-    msg!(
-        "Deposits: {}, Borrows: {}",
-        obligation.deposits.len(),
-        obligation.borrows.len()
-    );
+        let param = registry
+            .risk_params
+            .iter_mut()
+            .find(|p| {
+                (p.asset_id_a == asset_id_a && p.asset_id_b == asset_id_b)
+                    || (p.asset_id_a == asset_id_b && p.asset_id_b == asset_id_a)
+            })
+            .ok_or(ErrorCode::MissingRiskParam)?;
 
-    // If no borrows, obligation is healthy by default
-    if obligation.borrows.is_empty() {
-        msg!("Health: OK (no borrows)");
-        return Ok(());
+        param.max_open_interest = max_open_interest;
+
+        msg!(
+            "Pair {}-{} open interest cap set to {}",
+            asset_id_a,
+            asset_id_b,
+            max_open_interest
+        );
+        Ok(())
     }
 
-    // Calculate total deposit and borrow values
-    let mut deposit_values: Vec<(u8, u64)> = Vec::new(); // (asset_id, value)
-    let mut borrow_values: Vec<(u8, u64)> = Vec::new(); // (asset_id, value)
-    let mut total_deposit_value = 0u64;
-    let mut total_borrow_value = 0u64;
+    pub fn add_risk_param(
+        ctx: Context<ManageAssetRegistry>,
+        asset_id_a: u8,
+        asset_id_b: u8,
+        risk_level: u8,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
 
-    // Calculate deposit values
-    for deposit in &obligation.deposits {
-        let asset = registry
-            .assets
-            .iter()
-            .find(|a| a.id == deposit.asset_id)
-            .ok_or(ErrorCode::AssetNotFound)?;
+        // Verify both assets exist
+        if !registry.assets.iter().any(|a| a.id == asset_id_a) {
+            return Err(ErrorCode::AssetNotFound.into());
+        }
+        if !registry.assets.iter().any(|a| a.id == asset_id_b) {
+            return Err(ErrorCode::AssetNotFound.into());
+        }
 
-        let value = deposit.amount.saturating_mul(asset.price);
-        deposit_values.push((deposit.asset_id, value));
-        total_deposit_value = total_deposit_value.saturating_add(value);
+        // Check if pair already exists
+        if registry.risk_params.iter().any(|p| {
+            (p.asset_id_a == asset_id_a && p.asset_id_b == asset_id_b)
+                || (p.asset_id_a == asset_id_b && p.asset_id_b == asset_id_a)
+        }) {
+            return Err(ErrorCode::RiskParamAlreadyExists.into());
+        }
+        if registry.risk_params.len() >= AssetRegistry::MAX_RISK_PARAMS {
+            return Err(ErrorCode::RegistryFull.into());
+        }
+
+        registry.risk_params.push(PairRiskParam {
+            asset_id_a,
+            asset_id_b,
+            risk_level,
+            open_interest_a: 0,
+            open_interest_b: 0,
+            max_open_interest: 0,
+        });
 
         msg!(
-            "Deposit: id={}, amount={}, price={}, value={}",
-            deposit.asset_id,
-            deposit.amount,
-            asset.price,
-            value
+            "Added risk param: assets {}-{}, level={}",
+            asset_id_a,
+            asset_id_b,
+            risk_level
         );
+        Ok(())
     }
 
-    // Calculate borrow values
-    for borrow in &obligation.borrows {
-        let asset = registry
-            .assets
-            .iter()
-            .find(|a| a.id == borrow.asset_id)
-            .ok_or(ErrorCode::AssetNotFound)?;
-
-        let value = borrow.amount.saturating_mul(asset.price);
-        borrow_values.push((borrow.asset_id, value));
-        total_borrow_value = total_borrow_value.saturating_add(value);
+    pub fn set_position_minimums(
+        ctx: Context<ManageAssetRegistry>,
+        min_deposit_value: u64,
+        min_borrow_value: u64,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+        registry.min_deposit_value = min_deposit_value;
+        registry.min_borrow_value = min_borrow_value;
 
         msg!(
-            "Borrow: id={}, amount={}, price={}, value={}",
-            borrow.asset_id,
-            borrow.amount,
-            asset.price,
-            value
+            "Position minimums set: deposit={}, borrow={}",
+            min_deposit_value,
+            min_borrow_value
         );
+        Ok(())
     }
 
-    msg!("Total deposit value: {}", total_deposit_value);
-    msg!("Total borrow value: {}", total_borrow_value);
+    pub fn set_max_leverage(
+        ctx: Context<ManageAssetRegistry>,
+        max_leverage_bps: u32,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+        registry.max_leverage_bps = max_leverage_bps;
 
-    // Complex health score calculation
-    // Health = Sum(deposit_i * Sum(borrow_share_j * risk_factor_ij)) / total_borrow
-    let mut weighted_health_score = 0u64;
+        msg!("Max leverage set to {} bps", max_leverage_bps);
+        Ok(())
+    }
 
-    // For each deposit
-    for (deposit_id, deposit_value) in &deposit_values {
-        msg!("Processing deposit {}: value={}", deposit_id, deposit_value);
-        let mut deposit_risk_sum = 0u64;
+    pub fn set_warning_threshold(
+        ctx: Context<ManageAssetRegistry>,
+        warning_health_x1000: u64,
+    ) -> Result<()> {
+        // A warning level below 1.0 would never fire before liquidation
+        if warning_health_x1000 != 0 && warning_health_x1000 <= 1000 {
+            return Err(ErrorCode::InvalidWarningThreshold.into());
+        }
 
-        // For each borrow
-        for (borrow_id, borrow_value) in &borrow_values {
-            // Find risk parameter for this deposit-borrow pair
-            let risk_param = registry.risk_params.iter().find(|p| {
-                (p.asset_id_a == *deposit_id && p.asset_id_b == *borrow_id)
-                    || (p.asset_id_a == *borrow_id && p.asset_id_b == *deposit_id)
-            });
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+        registry.warning_health_x1000 = warning_health_x1000;
 
-            let risk_level = if let Some(param) = risk_param {
-                param.risk_level as u64
-            } else {
-                // Default risk level if pair not found
-                msg!(
-                    "  Warning: No risk param for pair {}-{}, using default 50",
-                    deposit_id,
-                    borrow_id
-                );
-                50
-            };
+        msg!("Warning health threshold set to {}", warning_health_x1000);
+        Ok(())
+    }
 
-            // Calculate borrow share (scaled by 100 for precision)
-            let borrow_share = borrow_value
-                .saturating_mul(100)
-                .checked_div(total_borrow_value)
-                .unwrap_or(0);
+    pub fn set_liquidation_params(
+        ctx: Context<ManageAssetRegistry>,
+        liquidation_bonus_bps: u16,
+        close_factor_bps: u16,
+    ) -> Result<()> {
+        // A zero close factor would make every obligation unliquidatable
+        if liquidation_bonus_bps > 10_000 || close_factor_bps == 0 || close_factor_bps > 10_000 {
+            return Err(ErrorCode::InvalidLiquidationParams.into());
+        }
 
-            // Add to deposit risk sum: borrow_share * risk_level
-            let risk_contribution = borrow_share.saturating_mul(risk_level);
-            deposit_risk_sum = deposit_risk_sum.saturating_add(risk_contribution);
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+        registry.liquidation_bonus_bps = liquidation_bonus_bps;
+        registry.close_factor_bps = close_factor_bps;
 
-            msg!(
-                "  Pair {}-{}: borrow_value={}, share={}%, risk={}, contribution={}",
-                deposit_id,
-                borrow_id,
-                borrow_value,
-                borrow_share,
-                risk_level,
-                risk_contribution
-            );
+        msg!(
+            "Liquidation params set: bonus={} bps, close factor={} bps",
+            liquidation_bonus_bps,
+            close_factor_bps
+        );
+        Ok(())
+    }
+
+    /// Slots an obligation is protected after a liquidation (0 = disabled)
+    pub fn set_liquidation_cooldown(
+        ctx: Context<ManageAssetRegistry>,
+        cooldown_slots: u64,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+        registry.liquidation_cooldown_slots = cooldown_slots;
+
+        msg!("Liquidation cooldown set to {} slots", cooldown_slots);
+        Ok(())
+    }
+
+    pub fn import_assets(
+        ctx: Context<ManageAssetRegistry>,
+        assets: Vec<AssetInfo>,
+        risk_params: Vec<PairRiskParam>,
+    ) -> Result<()> {
+        let registry = &mut ctx.accounts.asset_registry;
+        registry.bump_admin_nonce()?;
+
+        if registry.assets.len() + assets.len() > AssetRegistry::MAX_ASSETS
+            || registry.risk_params.len() + risk_params.len() > AssetRegistry::MAX_RISK_PARAMS
+        {
+            return Err(ErrorCode::RegistryFull.into());
         }
 
-        // Multiply deposit value by its weighted risk (divide by 100 to adjust for scaling)
-        let weighted_deposit_value = deposit_value
-            .saturating_mul(deposit_risk_sum)
-            .checked_div(100)
-            .unwrap_or(0);
+        // Assets keep their original ids so imported obligations line up
+        for mut asset in assets {
+            if registry.assets.iter().any(|a| {
+                a.id == asset.id || (asset.mint != Pubkey::default() && a.mint == asset.mint)
+            }) {
+                return Err(ErrorCode::AssetAlreadyExists.into());
+            }
+            // A fresh deployment has no obligations referencing the asset
+            asset.open_positions = 0;
+            asset.reserve = Reserve::new(Clock::get()?.slot);
+            registry.assets.push(asset);
+        }
 
-        weighted_health_score = weighted_health_score.saturating_add(weighted_deposit_value);
+        for mut param in risk_params {
+            if !registry.assets.iter().any(|a| a.id == param.asset_id_a)
+                || !registry.assets.iter().any(|a| a.id == param.asset_id_b)
+            {
+                return Err(ErrorCode::AssetNotFound.into());
+            }
+            if registry.risk_params.iter().any(|p| {
+                (p.asset_id_a == param.asset_id_a && p.asset_id_b == param.asset_id_b)
+                    || (p.asset_id_a == param.asset_id_b && p.asset_id_b == param.asset_id_a)
+            }) {
+                return Err(ErrorCode::RiskParamAlreadyExists.into());
+            }
+            // Open interest is rebuilt as imported obligations borrow again
+            param.open_interest_a = 0;
+            param.open_interest_b = 0;
+            registry.risk_params.push(param);
+        }
 
         msg!(
-            "  Deposit {} total: risk_sum={}, weighted_value={}",
-            deposit_id,
-            deposit_risk_sum,
-            weighted_deposit_value
+            "Imported registry: {} assets, {} risk params",
+            registry.assets.len(),
+            registry.risk_params.len()
         );
+        Ok(())
     }
 
-    // Final health score calculation
-    // We have: weighted_health_score (already divided by 100 once)
-    // Need to divide by total_borrow_value
-    // But we want to keep decimal precision, so multiply by 1000 first
-    let final_health_score_x1000 = weighted_health_score
-        .saturating_mul(1000)
-        .checked_div(total_borrow_value)
-        .unwrap_or(0)
-        .checked_div(100) // Divide by 100 for the risk scaling
-        .unwrap_or(0);
+    /// Fails unless the registry admin nonce equals `expected_nonce`.
+    ///
+    /// Prepended to a prepared admin transaction, it makes the transaction
+    /// fail cleanly if any other authority instruction landed since the
+    /// proposal was drafted.
+    pub fn check_admin_nonce(ctx: Context<ReadAssetRegistry>, expected_nonce: u64) -> Result<()> {
+        let admin_nonce = ctx.accounts.asset_registry.admin_nonce;
+        if admin_nonce != expected_nonce {
+            msg!(
+                "Admin nonce mismatch: expected {}, found {}",
+                expected_nonce,
+                admin_nonce
+            );
+            return Err(ErrorCode::AdminNonceMismatch.into());
+        }
+        Ok(())
+    }
 
-    msg!("=== FINAL CALCULATION ===");
-    msg!("Weighted health score sum: {}", weighted_health_score);
-    msg!("Total borrow value: {}", total_borrow_value);
-    msg!("Health score x1000: {}", final_health_score_x1000);
-    msg!(
-        "Health score: {}.{}",
-        final_health_score_x1000 / 1000,
-        final_health_score_x1000 % 1000
-    );
+    /// Checks registry invariants and emits a `RegistryReport`. Anyone may
+    /// call it; violations are reported rather than failing the instruction.
+    pub fn verify_registry(ctx: Context<ReadAssetRegistry>) -> Result<()> {
+        let registry = &ctx.accounts.asset_registry;
+        let issues = registry_issues(registry);
 
-    // Check if healthy (health score should be >= 1000 for 1.0 or 100% collateralization)
-    if final_health_score_x1000 < 1000 {
-        msg!(
-            "⚠️ WARNING: Health score {}.{} is below 1.0 - Position at risk!",
-            final_health_score_x1000 / 1000,
-            final_health_score_x1000 % 1000
-        );
-        return Err(ErrorCode::Unhealthy.into());
-    } else {
+        for issue in &issues {
+            msg!("Registry issue: {:?}", issue);
+        }
         msg!(
-            "✓ Health check PASSED - Score: {}.{}",
-            final_health_score_x1000 / 1000,
-            final_health_score_x1000 % 1000
+            "Registry check: {} assets, {} risk params, {} issues",
+            registry.assets.len(),
+            registry.risk_params.len(),
+            issues.len()
         );
+        emit!(RegistryReport {
+            assets: registry.assets.len() as u8,
+            risk_params: registry.risk_params.len() as u8,
+            issues,
+        });
+        Ok(())
     }
 
-    msg!("=== HEALTH CHECK END ===");
-    Ok(())
+    /// Returns up to `limit` assets and risk params starting at `offset`; a
+    /// full registry exceeds the return data limit, so clients page until
+    /// both lists come back empty.
+    pub fn export_registry(
+        ctx: Context<ReadAssetRegistry>,
+        offset: u8,
+        limit: u8,
+    ) -> Result<RegistrySnapshot> {
+        let registry = &ctx.accounts.asset_registry;
+        let (offset, limit) = (offset as usize, limit as usize);
+
+        Ok(RegistrySnapshot {
+            assets: registry
+                .assets
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .cloned()
+                .collect(),
+            risk_params: registry
+                .risk_params
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .cloned()
+                .collect(),
+        })
+    }
+
+    // ========== OBLIGATION INSTRUCTIONS ==========
+
+    pub fn init_obligation(ctx: Context<InitObligation>, mode: MarginMode) -> Result<()> {
+        let gates = &ctx.accounts.feature_gates.active;
+        require_feature(
+            mode != MarginMode::Isolated || gates.isolated_margin,
+            "isolated_margin",
+        )?;
+
+        count_owner_obligation(
+            &mut ctx.accounts.owner_obligations,
+            &ctx.accounts.asset_registry,
+        )?;
+
+        let owner = ctx.accounts.owner.key();
+        open_obligation(
+            &mut ctx.accounts.obligation,
+            &mut ctx.accounts.obligation_directory,
+            &mut ctx.accounts.obligation_page,
+            owner,
+            owner.to_bytes(),
+            mode,
+        )
+    }
+
+    /// Opens an obligation whose address is derived from `hash(owner, salt)`
+    /// instead of the owner key, so it cannot be looked up from the wallet.
+    ///
+    /// This only hides the address derivation. The obligation still stores
+    /// `owner` in its data, the owner signs every instruction on it and
+    /// `OwnerObligations` counts it, so anyone reading program accounts or
+    /// transactions can still link it to the wallet.
+    pub fn init_private_obligation(
+        ctx: Context<InitPrivateObligation>,
+        mode: MarginMode,
+        salt: [u8; 32],
+    ) -> Result<()> {
+        let gates = &ctx.accounts.feature_gates.active;
+        require_feature(gates.private_obligations, "private_obligations")?;
+        require_feature(
+            mode != MarginMode::Isolated || gates.isolated_margin,
+            "isolated_margin",
+        )?;
+
+        count_owner_obligation(
+            &mut ctx.accounts.owner_obligations,
+            &ctx.accounts.asset_registry,
+        )?;
+
+        let owner = ctx.accounts.owner.key();
+        open_obligation(
+            &mut ctx.accounts.obligation,
+            &mut ctx.accounts.obligation_directory,
+            &mut ctx.accounts.obligation_page,
+            owner,
+            private_obligation_seed(&owner, &salt),
+            mode,
+        )
+    }
+
+    pub fn close_obligation(ctx: Context<CloseObligation>) -> Result<()> {
+        let obligation = &ctx.accounts.obligation;
+
+        if !obligation.deposits.is_empty() || !obligation.borrows.is_empty() {
+            return Err(ErrorCode::ObligationNotEmpty.into());
+        }
+
+        // Leave a tombstone so later indexes keep their page slots
+        let slot = (obligation.index % ObligationPage::CAPACITY as u64) as usize;
+        ctx.accounts.obligation_page.seeds[slot] = [0u8; 32];
+
+        let directory = &mut ctx.accounts.obligation_directory;
+        directory.open_obligations = directory
+            .open_obligations
+            .checked_sub(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        // Obligations opened before per-owner counting are not in the count
+        let owner_obligations = &mut ctx.accounts.owner_obligations;
+        owner_obligations.open = owner_obligations.open.saturating_sub(1);
+
+        // A closed obligation must not stay on record as the worst one
+        if let Some(worst) = ctx.accounts.worst_obligation.as_mut() {
+            if worst.obligation == obligation.key() {
+                worst.clear();
+            }
+        }
+
+        msg!(
+            "Obligation closed for owner: {}, index={}",
+            obligation.owner,
+            obligation.index
+        );
+        Ok(())
+    }
+
+    /// Returns `(index, address, health)` for up to `limit` obligations from the
+    /// page containing `start_index`. Obligation accounts for each non-empty
+    /// slot are passed, in order, as remaining accounts.
+    pub fn scan_obligations(
+        ctx: Context<ScanObligations>,
+        start_index: u64,
+        limit: u8,
+    ) -> Result<Vec<ObligationHealth>> {
+        let registry = &ctx.accounts.asset_registry;
+        let page = &ctx.accounts.obligation_page;
+
+        // Stay within one page and the return data limit
+        let first_slot = (start_index % ObligationPage::CAPACITY as u64) as usize;
+        let count = (limit as usize).min(ObligationHealth::MAX_PER_SCAN);
+        let mut remaining = ctx.remaining_accounts.iter();
+        let mut results = Vec::new();
+
+        for (offset, seed) in page.seeds.iter().enumerate().skip(first_slot).take(count) {
+            // Closed obligations have no account to load
+            if *seed == [0u8; 32] {
+                continue;
+            }
+
+            let info = remaining
+                .next()
+                .ok_or(ErrorCode::InvalidObligationAccount)?;
+            if info.owner != &crate::ID {
+                return Err(ErrorCode::InvalidObligationAccount.into());
+            }
+            let obligation = Obligation::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+
+            let index = start_index - first_slot as u64 + offset as u64;
+            if obligation.seed != *seed || obligation.index != index {
+                return Err(ErrorCode::InvalidObligationAccount.into());
+            }
+
+            results.push(ObligationHealth {
+                index,
+                obligation: info.key(),
+                health_x1000: compute_health(&obligation, registry)?,
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// Records `obligation` as the least healthy known obligation when it is
+    /// below the current record, or refreshes the record when it already is
+    /// the recorded one. The record is also replaced once it is older than
+    /// `WorstObligation::MAX_AGE_SLOTS`, or when the recorded obligation is
+    /// passed as a remaining account and no longer exists. Anyone may call it,
+    /// and it emits a MarginCall for an obligation in the warning band.
+    pub fn refresh_worst_obligation(ctx: Context<RefreshWorstObligation>) -> Result<()> {
+        let health_x1000 = compute_health(&ctx.accounts.obligation, &ctx.accounts.asset_registry)?;
+        emit_margin_call(
+            &ctx.accounts.obligation,
+            &ctx.accounts.asset_registry,
+            health_x1000,
+        )?;
+        let obligation = ctx.accounts.obligation.key();
+        let slot = Clock::get()?.slot;
+        let worst = &mut ctx.accounts.worst_obligation;
+
+        let record_closed = ctx
+            .remaining_accounts
+            .iter()
+            .any(|info| info.key == &worst.obligation && info.owner != &crate::ID);
+        let record_stale = slot.saturating_sub(worst.slot) > WorstObligation::MAX_AGE_SLOTS;
+
+        if worst.obligation == Pubkey::default()
+            || worst.obligation == obligation
+            || health_x1000 < worst.health_x1000
+            || record_closed
+            || record_stale
+        {
+            worst.obligation = obligation;
+            worst.health_x1000 = health_x1000;
+            worst.slot = slot;
+            msg!(
+                "Worst obligation: {} health x1000={}",
+                obligation,
+                health_x1000
+            );
+        }
+        Ok(())
+    }
+
+    pub fn add_deposit(ctx: Context<ModifyObligation>, asset_id: u8, amount: u64) -> Result<()> {
+        ctx.accounts.obligation.bump_sequence()?;
+        ctx.accounts.market_stats.record(Operation::Deposit)?;
+        apply_deposit(
+            &mut ctx.accounts.obligation,
+            &mut ctx.accounts.asset_registry,
+            asset_id,
+            amount,
+            Clock::get()?.slot,
+        )?;
+        record_health_prices(&mut ctx.accounts.obligation, &ctx.accounts.asset_registry)?;
+        emit!(ObligationUpdated {
+            owner: ctx.accounts.obligation.owner,
+            sequence: ctx.accounts.obligation.sequence,
+            update: ObligationUpdate::Deposited { asset_id, amount },
+        });
+
+        transfer_to_vault(ctx.accounts, asset_id, amount)
+    }
+
+    /// Borrows `amount` of the asset, paying custodied assets out of their
+    /// vault. Returns how far the borrow moved the obligation's health.
+    ///
+    /// Oracle-priced assets are read from their feeds before the health
+    /// check; pass the feeds and the Chainlink program as remaining accounts,
+    /// next to any co-signers.
+    pub fn borrow(
+        ctx: Context<ModifyObligation>,
+        asset_id: u8,
+        amount: u64,
+    ) -> Result<HealthChange> {
+        require_co_signers(&ctx.accounts.obligation, ctx.remaining_accounts)?;
+        ctx.accounts.obligation.bump_sequence()?;
+        ctx.accounts.market_stats.record(Operation::Borrow)?;
+        let clock = Clock::get()?;
+        let mut valued = held_asset_ids(&ctx.accounts.obligation);
+        valued.push(asset_id);
+        refresh_oracle_prices(
+            &mut ctx.accounts.asset_registry,
+            &valued,
+            ctx.remaining_accounts,
+            &clock,
+        )?;
+        let change = apply_borrow(
+            &mut ctx.accounts.obligation,
+            &mut ctx.accounts.asset_registry,
+            asset_id,
+            amount,
+            clock.slot,
+        )?;
+        record_health_prices(&mut ctx.accounts.obligation, &ctx.accounts.asset_registry)?;
+
+        transfer_from_vault(ctx.accounts, asset_id, amount)?;
+        Ok(change)
+    }
+
+    /// Deprecated: use `borrow`.
+    pub fn add_borrow(
+        ctx: Context<ModifyObligation>,
+        asset_id: u8,
+        amount: u64,
+    ) -> Result<HealthChange> {
+        borrow(ctx, asset_id, amount)
+    }
+
+    /// Returns how far the withdrawal moved the obligation's health.
+    pub fn remove_deposit(
+        ctx: Context<ModifyObligation>,
+        asset_id: u8,
+        amount: u64,
+    ) -> Result<HealthChange> {
+        require_co_signers(&ctx.accounts.obligation, ctx.remaining_accounts)?;
+
+        // Assets with a cooldown must go through request_withdraw/execute_withdraw
+        let cooldown = ctx
+            .accounts
+            .asset_registry
+            .assets
+            .iter()
+            .find(|a| a.id == asset_id)
+            .map(|a| a.withdraw_cooldown_slots)
+            .unwrap_or(0);
+        if cooldown > 0 {
+            return Err(position_error(
+                ctx.accounts.obligation.owner,
+                ctx.accounts.obligation.sequence,
+                ErrorCode::WithdrawalCooldownRequired,
+                asset_id,
+                amount,
+                0,
+            ));
+        }
+
+        withdraw_deposit(ctx.accounts, ctx.remaining_accounts, asset_id, amount)
+    }
+
+    /// Starts the withdrawal cooldown for `amount` of a deposit, replacing any
+    /// pending request.
+    pub fn request_withdraw(
+        ctx: Context<ModifyObligation>,
+        asset_id: u8,
+        amount: u64,
+    ) -> Result<()> {
+        // Co-signers approve the request; execute_withdraw only finalizes it
+        require_co_signers(&ctx.accounts.obligation, ctx.remaining_accounts)?;
+        let registry = &ctx.accounts.asset_registry;
+        let obligation = &mut ctx.accounts.obligation;
+        obligation.bump_sequence()?;
+        let owner = obligation.owner;
+        let sequence = obligation.sequence;
+
+        let asset = assert_operation_allowed(registry, asset_id, Operation::Withdraw)
+            .map_err(|e| position_error(owner, sequence, e, asset_id, amount, 0))?;
+        let available = obligation
+            .deposits
+            .iter()
+            .find(|p| p.asset_id == asset_id)
+            .map(|p| p.amount)
+            .unwrap_or(0);
+        if amount == 0 || available < amount {
+            return Err(position_error(
+                owner,
+                sequence,
+                ErrorCode::InsufficientDeposit,
+                asset_id,
+                amount,
+                available,
+            ));
+        }
+
+        let ready_slot = Clock::get()?
+            .slot
+            .checked_add(asset.withdraw_cooldown_slots)
+            .ok_or(ErrorCode::MathOverflow)?;
+        obligation.pending_withdrawal = Some(PendingWithdrawal {
+            asset_id,
+            amount,
+            ready_slot,
+        });
+
+        msg!(
+            "Withdrawal requested: asset_id={}, amount={}, ready_slot={}",
+            asset_id,
+            amount,
+            ready_slot
+        );
+        emit!(ObligationUpdated {
+            owner,
+            sequence,
+            update: ObligationUpdate::WithdrawalRequested {
+                asset_id,
+                amount,
+                ready_slot,
+            },
+        });
+        Ok(())
+    }
+
+    pub fn execute_withdraw(ctx: Context<ModifyObligation>) -> Result<()> {
+        let pending = ctx
+            .accounts
+            .obligation
+            .pending_withdrawal
+            .take()
+            .ok_or(ErrorCode::NoPendingWithdrawal)?;
+
+        let current_slot = Clock::get()?.slot;
+        if current_slot < pending.ready_slot {
+            msg!(
+                "Withdrawal not ready: current_slot={}, ready_slot={}",
+                current_slot,
+                pending.ready_slot
+            );
+            return Err(ErrorCode::WithdrawalCooldownActive.into());
+        }
+
+        withdraw_deposit(
+            ctx.accounts,
+            ctx.remaining_accounts,
+            pending.asset_id,
+            pending.amount,
+        )?;
+        Ok(())
+    }
+
+    /// Repays `amount` of a borrow, pulling custodied assets from the owner's
+    /// token account into their vault.
+    pub fn repay(ctx: Context<ModifyObligation>, asset_id: u8, amount: u64) -> Result<()> {
+        ctx.accounts.obligation.bump_sequence()?;
+        ctx.accounts.market_stats.record(Operation::Repay)?;
+        apply_repay(
+            &mut ctx.accounts.obligation,
+            &mut ctx.accounts.asset_registry,
+            asset_id,
+            amount,
+            Clock::get()?.slot,
+        )?;
+        record_health_prices(&mut ctx.accounts.obligation, &ctx.accounts.asset_registry)?;
+        emit!(ObligationUpdated {
+            owner: ctx.accounts.obligation.owner,
+            sequence: ctx.accounts.obligation.sequence,
+            update: ObligationUpdate::Repaid { asset_id, amount },
+        });
+
+        transfer_to_vault(ctx.accounts, asset_id, amount)
+    }
+
+    /// Deprecated: use `repay`.
+    pub fn remove_borrow(ctx: Context<ModifyObligation>, asset_id: u8, amount: u64) -> Result<()> {
+        repay(ctx, asset_id, amount)
+    }
+
+    /// Borrows `value` worth of the asset at the current price, aborting if
+    /// that price is outside `[min_price, max_price]`. An oracle-priced asset
+    /// is read from its feed first, passed as a remaining account.
+    pub fn borrow_value(
+        ctx: Context<ModifyObligation>,
+        asset_id: u8,
+        value: u64,
+        min_price: u64,
+        max_price: u64,
+    ) -> Result<HealthChange> {
+        refresh_oracle_prices(
+            &mut ctx.accounts.asset_registry,
+            &[asset_id],
+            ctx.remaining_accounts,
+            &Clock::get()?,
+        )?;
+        check_price_band(&ctx.accounts.asset_registry, asset_id, min_price, max_price)?;
+        let amount = value_to_amount(&ctx.accounts.asset_registry, asset_id, value)?;
+
+        msg!("Borrow by value: value={}, amount={}", value, amount);
+        borrow(ctx, asset_id, amount)
+    }
+
+    /// Repays `value` worth of the asset at the current price, capped at the
+    /// outstanding borrow and aborting if that price is outside
+    /// `[min_price, max_price]`. An oracle-priced asset is read from its feed
+    /// first, passed as a remaining account.
+    pub fn repay_value(
+        ctx: Context<ModifyObligation>,
+        asset_id: u8,
+        value: u64,
+        min_price: u64,
+        max_price: u64,
+    ) -> Result<()> {
+        refresh_oracle_prices(
+            &mut ctx.accounts.asset_registry,
+            &[asset_id],
+            ctx.remaining_accounts,
+            &Clock::get()?,
+        )?;
+        check_price_band(&ctx.accounts.asset_registry, asset_id, min_price, max_price)?;
+        let amount = value_to_amount(&ctx.accounts.asset_registry, asset_id, value)?;
+        // Cap against the debt owed now, not at the last settlement
+        refresh_interest(
+            &mut ctx.accounts.obligation,
+            &mut ctx.accounts.asset_registry,
+            Clock::get()?.slot,
+        )?;
+        let obligation = &ctx.accounts.obligation;
+        let outstanding = obligation
+            .borrows
+            .iter()
+            .find(|p| p.asset_id == asset_id)
+            .ok_or_else(|| {
+                position_error(
+                    obligation.owner,
+                    obligation.sequence,
+                    ErrorCode::BorrowNotFound,
+                    asset_id,
+                    amount,
+                    0,
+                )
+            })?
+            .amount;
+        let amount = amount.min(outstanding);
+
+        msg!("Repay by value: value={}, amount={}", value, amount);
+        repay(ctx, asset_id, amount)
+    }
+
+    pub fn set_seize_order(ctx: Context<ModifyObligation>, order: Vec<u8>) -> Result<()> {
+        let obligation = &mut ctx.accounts.obligation;
+        let registry = &ctx.accounts.asset_registry;
+        assert_not_paused(registry)?;
+
+        if order.len() > Obligation::MAX_SEIZE_ORDER {
+            return Err(ErrorCode::InvalidSeizeOrder.into());
+        }
+
+        for (i, asset_id) in order.iter().enumerate() {
+            // Verify asset exists
+            if !registry.assets.iter().any(|a| a.id == *asset_id) {
+                return Err(ErrorCode::AssetNotFound.into());
+            }
+            // Reject duplicates
+            if order[..i].contains(asset_id) {
+                return Err(ErrorCode::InvalidSeizeOrder.into());
+            }
+        }
+
+        obligation.bump_sequence()?;
+        obligation.seize_order = order;
+
+        msg!("Seize order set: {:?}", obligation.seize_order);
+        emit!(ObligationUpdated {
+            owner: obligation.owner,
+            sequence: obligation.sequence,
+            update: ObligationUpdate::SeizeOrderSet,
+        });
+        Ok(())
+    }
+
+    /// Repays part of an unhealthy obligation's borrow on the owner's behalf
+    /// and pays the liquidator collateral worth the repaid value plus the
+    /// liquidation bonus. At most the close factor of the borrow can be
+    /// repaid per call. Oracle-priced assets are read from the feeds passed
+    /// as remaining accounts, with the Chainlink program.
+    pub fn liquidate(
+        ctx: Context<Liquidate>,
+        repay_asset_id: u8,
+        amount: u64,
+        collateral_asset_id: u8,
+    ) -> Result<()> {
+        let obligation = &mut ctx.accounts.obligation;
+        obligation.bump_sequence()?;
+        let owner = obligation.owner;
+        let sequence = obligation.sequence;
+        ctx.accounts.market_stats.record_liquidation()?;
+        let clock = Clock::get()?;
+        refresh_oracle_prices(
+            &mut ctx.accounts.asset_registry,
+            &held_asset_ids(obligation),
+            ctx.remaining_accounts,
+            &clock,
+        )?;
+
+        let liquidation = apply_liquidation(
+            obligation,
+            &mut ctx.accounts.asset_registry,
+            repay_asset_id,
+            amount,
+            collateral_asset_id,
+            clock.slot,
+        )?;
+        record_health_prices(obligation, &ctx.accounts.asset_registry)?;
+
+        msg!(
+            "Liquidated {}: repaid {} of asset {}, seized {} of asset {}",
+            owner,
+            amount,
+            repay_asset_id,
+            liquidation.seized,
+            collateral_asset_id
+        );
+        emit!(Liquidated {
+            owner,
+            sequence,
+            liquidator: ctx.accounts.liquidator.key(),
+            repay_asset_id,
+            repay_amount: amount,
+            collateral_asset_id,
+            seized_amount: liquidation.seized,
+            health_before_x1000: liquidation.health_before_x1000,
+            health_after_x1000: liquidation.health_after_x1000,
+        });
+
+        let accounts = &ctx.accounts;
+        if let Some(mint) = custodied_mint(&accounts.asset_registry, repay_asset_id)? {
+            let tokens = checked_token_accounts(
+                accounts.repay_mint.as_ref(),
+                accounts.repay_vault.as_ref(),
+                accounts.liquidator_repay_account.as_ref(),
+                accounts.vault_authority.as_ref(),
+                accounts.token_program.as_ref(),
+                mint,
+            )?;
+            pull_tokens(&tokens, accounts.liquidator.to_account_info(), amount)?;
+        }
+        if let Some(mint) = custodied_mint(&accounts.asset_registry, collateral_asset_id)? {
+            let tokens = checked_token_accounts(
+                accounts.collateral_mint.as_ref(),
+                accounts.collateral_vault.as_ref(),
+                accounts.liquidator_collateral_account.as_ref(),
+                accounts.vault_authority.as_ref(),
+                accounts.token_program.as_ref(),
+                mint,
+            )?;
+            push_tokens(&tokens, liquidation.seized)?;
+        }
+        Ok(())
+    }
+
+    /// Caps the obligation's total borrow value; 0 removes the cap. Existing
+    /// borrows above a new cap stay open, but no further borrows are allowed.
+    pub fn set_credit_limit(ctx: Context<SetCreditLimit>, credit_limit: u64) -> Result<()> {
+        ctx.accounts.asset_registry.bump_admin_nonce()?;
+        let obligation = &mut ctx.accounts.obligation;
+        obligation.bump_sequence()?;
+        obligation.credit_limit = credit_limit;
+
+        msg!(
+            "Credit limit for {} set to {}",
+            obligation.owner,
+            credit_limit
+        );
+        emit!(CreditLimitSet {
+            owner: obligation.owner,
+            sequence: obligation.sequence,
+            obligation: obligation.key(),
+            credit_limit,
+        });
+        Ok(())
+    }
+
+    /// Replaces the obligation's co-signer set. Borrows and withdrawals then
+    /// need `threshold` signatures among the owner and co-signers, passed as
+    /// remaining signer accounts; a threshold of 0 or 1 means the owner alone.
+    /// The current threshold must be met to change the set.
+    pub fn set_co_signers(
+        ctx: Context<ModifyObligation>,
+        co_signers: Vec<Pubkey>,
+        threshold: u8,
+    ) -> Result<()> {
+        require_co_signers(&ctx.accounts.obligation, ctx.remaining_accounts)?;
+        assert_not_paused(&ctx.accounts.asset_registry)?;
+        let obligation = &mut ctx.accounts.obligation;
+
+        if co_signers.len() > Obligation::MAX_CO_SIGNERS
+            || threshold as usize > co_signers.len() + 1
+        {
+            return Err(ErrorCode::InvalidCoSigners.into());
+        }
+        for (i, co_signer) in co_signers.iter().enumerate() {
+            if *co_signer == obligation.owner || co_signers[..i].contains(co_signer) {
+                return Err(ErrorCode::InvalidCoSigners.into());
+            }
+        }
+
+        obligation.bump_sequence()?;
+        obligation.co_signers = co_signers;
+        obligation.threshold = threshold;
+
+        msg!(
+            "Co-signers set: {} of {}",
+            threshold,
+            obligation.co_signers.len() + 1
+        );
+        emit!(ObligationUpdated {
+            owner: obligation.owner,
+            sequence: obligation.sequence,
+            update: ObligationUpdate::CoSignersSet { threshold },
+        });
+        Ok(())
+    }
+
+    // ========== DEBUG INSTRUCTION ==========
+
+    /// Returns the health change `operation` would cause, without applying it.
+    pub fn preview_health_impact(
+        ctx: Context<DebugReadData>,
+        operation: Operation,
+        asset_id: u8,
+        amount: u64,
+    ) -> Result<HealthChange> {
+        marginal_health_impact(
+            &ctx.accounts.obligation,
+            &ctx.accounts.asset_registry,
+            operation,
+            asset_id,
+            amount,
+        )
+    }
+
+    /// Logs how the obligation's positions and health changed since the last
+    /// call, then records a hash of the current state on the obligation and
+    /// returns that state. Pass the returned snapshot back as `previous` to
+    /// see the changes position by position; without it, or if it does not
+    /// match the recorded hash, the current positions are logged instead.
+    pub fn debug_diff(
+        ctx: Context<DebugDiff>,
+        previous: Option<ObligationSnapshot>,
+    ) -> Result<ObligationSnapshot> {
+        let obligation = &mut ctx.accounts.obligation;
+        let current = ObligationSnapshot {
+            deposits: obligation.deposits.clone(),
+            borrows: obligation.borrows.clone(),
+            health_x1000: compute_health(obligation, &ctx.accounts.asset_registry)?,
+            slot: Clock::get()?.slot,
+        };
+        let hash = current.hash()?;
+
+        if obligation.snapshot_slot == 0 {
+            msg!("=== SNAPSHOT CREATED ===");
+        } else if obligation.snapshot_hash == hash {
+            msg!("No changes since slot {}", obligation.snapshot_slot);
+        } else {
+            msg!("=== CHANGES SINCE SLOT {} ===", obligation.snapshot_slot);
+            let before = match previous {
+                Some(previous) if previous.hash()? == obligation.snapshot_hash => previous,
+                _ => {
+                    msg!("Previous snapshot not passed or stale; logging current positions");
+                    ObligationSnapshot {
+                        deposits: Vec::new(),
+                        borrows: Vec::new(),
+                        health_x1000: current.health_x1000,
+                        slot: obligation.snapshot_slot,
+                    }
+                }
+            };
+            log_position_diff("Deposit", &before.deposits, &current.deposits);
+            log_position_diff("Borrow", &before.borrows, &current.borrows);
+            if before.health_x1000 != current.health_x1000 {
+                msg!(
+                    "Health x1000: {} -> {}",
+                    before.health_x1000,
+                    current.health_x1000
+                );
+            }
+        }
+
+        obligation.snapshot_hash = hash;
+        obligation.snapshot_slot = current.slot;
+        Ok(current)
+    }
+
+    use chainlink_solana::Round;
+
+    pub fn execute(ctx: Context<Execute>) -> Result<()> {
+        let round: Round = chainlink::latest_round_data(
+            ctx.accounts.chainlink_program.to_account_info(),
+            ctx.accounts.chainlink_feed.to_account_info(),
+        )?;
+
+        let description: String = chainlink::description(
+            ctx.accounts.chainlink_program.to_account_info(),
+            ctx.accounts.chainlink_feed.to_account_info(),
+        )?;
+
+        let decimals: u8 = chainlink::decimals(
+            ctx.accounts.chainlink_program.to_account_info(),
+            ctx.accounts.chainlink_feed.to_account_info(),
+        )?;
+
+        let decimal: &mut Account<Decimal> = &mut ctx.accounts.decimal;
+        decimal.value = round.answer;
+        decimal.decimals = u32::from(decimals);
+
+        let decimal_print: Decimal = Decimal::new(round.answer, u32::from(decimals));
+        msg!("{} price is {}", description, decimal_print);
+        Ok(())
+    }
+}
+
+// ========== OBLIGATION SETUP ==========
+
+fn open_obligation(
+    obligation: &mut Obligation,
+    directory: &mut ObligationDirectory,
+    page: &mut ObligationPage,
+    owner: Pubkey,
+    seed: [u8; 32],
+    mode: MarginMode,
+) -> Result<()> {
+    // Append the seed to the directory; indexes never move so pages stay stable
+    *obligation = Obligation {
+        index: directory.total_created,
+        ..Obligation::new(owner, seed, mode)
+    };
+    page.seeds.push(seed);
+    directory.total_created = directory
+        .total_created
+        .checked_add(1)
+        .ok_or(ErrorCode::MathOverflow)?;
+    directory.open_obligations = directory
+        .open_obligations
+        .checked_add(1)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    msg!(
+        "Obligation initialized for owner: {}, mode={:?}, index={}",
+        obligation.owner,
+        obligation.mode,
+        obligation.index
+    );
+    Ok(())
+}
+
+/// Counts a new obligation against its owner, enforcing the registry cap.
+fn count_owner_obligation(
+    owner_obligations: &mut OwnerObligations,
+    registry: &AssetRegistry,
+) -> Result<()> {
+    let max = registry.max_obligations_per_owner;
+    if max > 0 && owner_obligations.open >= max as u32 {
+        msg!(
+            "Owner already has {} open obligations",
+            owner_obligations.open
+        );
+        return Err(ErrorCode::TooManyObligations.into());
+    }
+    owner_obligations.open = owner_obligations
+        .open
+        .checked_add(1)
+        .ok_or(ErrorCode::MathOverflow)?;
+    Ok(())
+}
+
+/// PDA seed for private obligations. Hashing the owner in prevents anyone from
+/// squatting on another wallet's default `[b"obligation", owner]` address.
+pub fn private_obligation_seed(owner: &Pubkey, salt: &[u8; 32]) -> [u8; 32] {
+    hashv(&[owner.as_ref(), salt.as_ref()]).to_bytes()
+}
+
+// ========== POSITION CHANGES ==========
+//
+// The account-free core of each obligation instruction: validates one
+// position change at `slot` and applies it to the obligation and the
+// registry. Instructions wrap these with sequence numbers, stats, co-signer
+// checks, price records and token movement; test scenarios call them
+// directly.
+
+fn apply_deposit(
+    obligation: &mut Obligation,
+    registry: &mut AssetRegistry,
+    asset_id: u8,
+    amount: u64,
+    slot: u64,
+) -> Result<()> {
+    let owner = obligation.owner;
+    let sequence = obligation.sequence;
+    refresh_interest(obligation, registry, slot)?;
+
+    assert_operation_allowed(registry, asset_id, Operation::Deposit)
+        .map_err(|e| position_error(owner, sequence, e, asset_id, amount, 0))?;
+    let health_before = compute_health(obligation, registry)?;
+
+    // Isolated obligations track a single collateral asset
+    if obligation.mode == MarginMode::Isolated
+        && obligation.deposits.iter().any(|p| p.asset_id != asset_id)
+    {
+        return Err(position_error(
+            owner,
+            sequence,
+            ErrorCode::IsolatedPairMismatch,
+            asset_id,
+            amount,
+            0,
+        ));
+    }
+
+    // Add or update deposit
+    if let Some(position) = obligation
+        .deposits
+        .iter_mut()
+        .find(|p| p.asset_id == asset_id)
+    {
+        let available = position.amount;
+        position.amount = position.amount.checked_add(amount).ok_or_else(|| {
+            position_error(
+                owner,
+                sequence,
+                ErrorCode::MathOverflow,
+                asset_id,
+                amount,
+                available,
+            )
+        })?;
+    } else {
+        obligation.deposits.push(Position {
+            asset_id,
+            amount,
+            borrow_index: 0,
+        });
+        track_open_position(registry, asset_id, true)?;
+    }
+    track_reserve(registry, asset_id, Operation::Deposit, amount, slot)?;
+    check_custody(obligation, registry)?;
+
+    // Reject positions too small to ever be liquidated profitably
+    if let Some(position) = obligation.deposits.iter().find(|p| p.asset_id == asset_id) {
+        check_position_minimum(
+            registry,
+            position,
+            registry.min_deposit_value,
+            ErrorCode::DepositBelowMinimum,
+        )?;
+    }
+
+    msg!("Added deposit: asset_id={}, amount={}", asset_id, amount);
+
+    // Perform health check
+    check_health_not_reduced(obligation, registry, health_before)?;
+    Ok(())
+}
+
+/// Returns how far the borrow moved the obligation's health.
+fn apply_borrow(
+    obligation: &mut Obligation,
+    registry: &mut AssetRegistry,
+    asset_id: u8,
+    amount: u64,
+    slot: u64,
+) -> Result<HealthChange> {
+    let owner = obligation.owner;
+    let sequence = obligation.sequence;
+    refresh_interest(obligation, registry, slot)?;
+    let borrow_index = accrue_reserve(registry, asset_id, slot)?;
+
+    assert_operation_allowed(registry, asset_id, Operation::Borrow)
+        .map_err(|e| position_error(owner, sequence, e, asset_id, amount, 0))?;
+    let health_before = compute_health(obligation, registry)?;
+
+    // Isolated obligations track a single borrow asset
+    if obligation.mode == MarginMode::Isolated
+        && obligation.borrows.iter().any(|p| p.asset_id != asset_id)
+    {
+        return Err(position_error(
+            owner,
+            sequence,
+            ErrorCode::IsolatedPairMismatch,
+            asset_id,
+            amount,
+            0,
+        ));
+    }
+
+    msg!("Adding borrow: asset_id={}, amount={}", asset_id, amount);
+    msg!(
+        "Current deposits: {}, borrows: {}",
+        obligation.deposits.len(),
+        obligation.borrows.len()
+    );
+
+    // Add or update borrow
+    if let Some(position) = obligation
+        .borrows
+        .iter_mut()
+        .find(|p| p.asset_id == asset_id)
+    {
+        let available = position.amount;
+        position.amount = position.amount.checked_add(amount).ok_or_else(|| {
+            position_error(
+                owner,
+                sequence,
+                ErrorCode::MathOverflow,
+                asset_id,
+                amount,
+                available,
+            )
+        })?;
+    } else {
+        obligation.borrows.push(Position {
+            asset_id,
+            amount,
+            borrow_index,
+        });
+        track_open_position(registry, asset_id, true)?;
+    }
+    track_reserve(registry, asset_id, Operation::Borrow, amount, slot)?;
+    track_open_interest(registry, obligation, asset_id, amount, true)
+        .map_err(|e| position_error(owner, sequence, e, asset_id, amount, 0))?;
+    check_custody(obligation, registry)?;
+
+    // Reject positions too small to ever be liquidated profitably
+    if let Some(position) = obligation.borrows.iter().find(|p| p.asset_id == asset_id) {
+        check_position_minimum(
+            registry,
+            position,
+            registry.min_borrow_value,
+            ErrorCode::BorrowBelowMinimum,
+        )?;
+    }
+
+    // Perform health check
+    check_prices_fresh(obligation, registry, slot)?;
+    let health_after = perform_health_check(obligation, registry)?;
+    check_leverage(obligation, registry)?;
+    check_credit_limit(obligation, registry)?;
+
+    Ok(report_health_change(
+        owner,
+        sequence,
+        health_before,
+        health_after,
+    ))
+}
+
+/// Returns how far the withdrawal moved the obligation's health.
+fn apply_withdraw(
+    obligation: &mut Obligation,
+    registry: &mut AssetRegistry,
+    asset_id: u8,
+    amount: u64,
+    slot: u64,
+) -> Result<HealthChange> {
+    let owner = obligation.owner;
+    let sequence = obligation.sequence;
+    refresh_interest(obligation, registry, slot)?;
+    assert_operation_allowed(registry, asset_id, Operation::Withdraw)
+        .map_err(|e| position_error(owner, sequence, e, asset_id, amount, 0))?;
+    let health_before = compute_health(obligation, registry)?;
+
+    msg!("Removing deposit: asset_id={}, amount={}", asset_id, amount);
+    msg!(
+        "Current deposits: {}, borrows: {}",
+        obligation.deposits.len(),
+        obligation.borrows.len()
+    );
+
+    if amount == 0 {
+        return Err(position_error(
+            owner,
+            sequence,
+            ErrorCode::AmountTooSmall,
+            asset_id,
+            amount,
+            0,
+        ));
+    }
+
+    let position = obligation
+        .deposits
+        .iter_mut()
+        .find(|p| p.asset_id == asset_id)
+        .ok_or_else(|| {
+            position_error(
+                owner,
+                sequence,
+                ErrorCode::DepositNotFound,
+                asset_id,
+                amount,
+                0,
+            )
+        })?;
+
+    if position.amount < amount {
+        return Err(position_error(
+            owner,
+            sequence,
+            ErrorCode::InsufficientDeposit,
+            asset_id,
+            amount,
+            position.amount,
+        ));
+    }
+
+    position.amount = position.amount.checked_sub(amount).unwrap();
+
+    // Partial withdrawals must not leave dust behind
+    check_position_minimum(
+        registry,
+        position,
+        registry.min_deposit_value,
+        ErrorCode::DepositBelowMinimum,
+    )?;
+
+    // Remove if zero
+    if position.amount == 0 {
+        obligation.deposits.retain(|p| p.asset_id != asset_id);
+        track_open_position(registry, asset_id, false)?;
+    }
+    track_reserve(registry, asset_id, Operation::Withdraw, amount, slot)?;
+
+    // Perform health check
+    check_prices_fresh(obligation, registry, slot)?;
+    let health_after = perform_health_check(obligation, registry)?;
+    check_leverage(obligation, registry)?;
+
+    Ok(report_health_change(
+        owner,
+        sequence,
+        health_before,
+        health_after,
+    ))
+}
+
+fn apply_repay(
+    obligation: &mut Obligation,
+    registry: &mut AssetRegistry,
+    asset_id: u8,
+    amount: u64,
+    slot: u64,
+) -> Result<()> {
+    let owner = obligation.owner;
+    let sequence = obligation.sequence;
+    refresh_interest(obligation, registry, slot)?;
+    assert_operation_allowed(registry, asset_id, Operation::Repay)
+        .map_err(|e| position_error(owner, sequence, e, asset_id, amount, 0))?;
+    let health_before = compute_health(obligation, registry)?;
+
+    msg!("Removing borrow: asset_id={}, amount={}", asset_id, amount);
+
+    if amount == 0 {
+        return Err(position_error(
+            owner,
+            sequence,
+            ErrorCode::AmountTooSmall,
+            asset_id,
+            amount,
+            0,
+        ));
+    }
+
+    let position = obligation
+        .borrows
+        .iter_mut()
+        .find(|p| p.asset_id == asset_id)
+        .ok_or_else(|| {
+            position_error(
+                owner,
+                sequence,
+                ErrorCode::BorrowNotFound,
+                asset_id,
+                amount,
+                0,
+            )
+        })?;
+
+    if position.amount < amount {
+        return Err(position_error(
+            owner,
+            sequence,
+            ErrorCode::InsufficientBorrow,
+            asset_id,
+            amount,
+            position.amount,
+        ));
+    }
+
+    position.amount = position.amount.checked_sub(amount).unwrap();
+
+    // Partial repayments must not leave dust behind
+    check_position_minimum(
+        registry,
+        position,
+        registry.min_borrow_value,
+        ErrorCode::BorrowBelowMinimum,
+    )?;
+
+    // Remove if zero
+    if position.amount == 0 {
+        obligation.borrows.retain(|p| p.asset_id != asset_id);
+        track_open_position(registry, asset_id, false)?;
+    }
+    track_open_interest(registry, obligation, asset_id, amount, false)?;
+    track_reserve(registry, asset_id, Operation::Repay, amount, slot)?;
+
+    // Perform health check
+    check_health_not_reduced(obligation, registry, health_before)?;
+    Ok(())
+}
+
+// ========== WITHDRAWAL ==========
+
+/// Withdraws a deposit after reading the obligation's oracle-priced assets
+/// from the feeds in `oracle_accounts`.
+fn withdraw_deposit(
+    accounts: &mut ModifyObligation,
+    oracle_accounts: &[AccountInfo],
+    asset_id: u8,
+    amount: u64,
+) -> Result<HealthChange> {
+    accounts.obligation.bump_sequence()?;
+    accounts.market_stats.record(Operation::Withdraw)?;
+    let clock = Clock::get()?;
+    refresh_oracle_prices(
+        &mut accounts.asset_registry,
+        &held_asset_ids(&accounts.obligation),
+        oracle_accounts,
+        &clock,
+    )?;
+    let change = apply_withdraw(
+        &mut accounts.obligation,
+        &mut accounts.asset_registry,
+        asset_id,
+        amount,
+        clock.slot,
+    )?;
+    record_health_prices(&mut accounts.obligation, &accounts.asset_registry)?;
+
+    transfer_from_vault(accounts, asset_id, amount)?;
+    Ok(change)
+}
+
+// ========== PRICE UPDATES ==========
+
+/// Caches a resolved price on the asset. A price outside the asset's bounds
+/// is not used: it trips the circuit breaker by pausing the market, so a
+/// decimal or feed mix-up never reaches the health math.
+///
+/// Tripping the breaker returns Ok on purpose: the transaction that posted
+/// the bad price commits, because an error would roll back the pause with it.
+fn store_price(registry: &mut AssetRegistry, id: u8, resolved: PriceResult) -> Result<()> {
+    let price = resolved.price;
+    let asset = registry
+        .assets
+        .iter_mut()
+        .find(|a| a.id == id)
+        .ok_or(ErrorCode::AssetNotFound)?;
+
+    if !asset.price_within_bounds(price) {
+        msg!(
+            "CIRCUIT BREAKER: asset {} price {} outside [{}, {}], pausing market",
+            id,
+            price,
+            asset.min_price,
+            asset.max_price
+        );
+        emit!(PriceOutOfBounds {
+            asset_id: id,
+            price,
+            min_price: asset.min_price,
+            max_price: asset.max_price,
+        });
+        registry.paused = true;
+        return Ok(());
+    }
+
+    asset.price = price;
+    asset.price_slot = resolved.slot;
+    msg!("Updated asset {} price to {}", id, price);
+    Ok(())
+}
+
+/// Reads the oracle-priced assets among `asset_ids` through their fallback
+/// chains, so the health check that follows values them at the feeds' latest
+/// answers. Authority-priced assets keep their cached price. An
+/// out-of-bounds answer fails the instruction rather than tripping the
+/// breaker, since the pause would be rolled back with it; `refresh_price`
+/// trips it.
+fn refresh_oracle_prices(
+    registry: &mut AssetRegistry,
+    asset_ids: &[u8],
+    accounts: &[AccountInfo],
+    clock: &Clock,
+) -> Result<()> {
+    for (i, &id) in asset_ids.iter().enumerate() {
+        if asset_ids[..i].contains(&id) {
+            continue;
+        }
+        let asset = registry
+            .assets
+            .iter()
+            .find(|a| a.id == id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+        if !oracle::has_oracle_feed(asset) {
+            continue;
+        }
+
+        let (resolved, origin) = oracle::resolve_with_fallback(asset, accounts, clock, registry)?;
+        if origin == PriceOrigin::Cached {
+            continue;
+        }
+        if !asset.price_within_bounds(resolved.price) {
+            msg!(
+                "Asset {} oracle price {} outside [{}, {}]",
+                id,
+                resolved.price,
+                asset.min_price,
+                asset.max_price
+            );
+            return Err(ErrorCode::InvalidPrice.into());
+        }
+        store_price(registry, id, resolved)?;
+    }
+    Ok(())
+}
+
+/// Asset ids of every position the obligation holds
+fn held_asset_ids(obligation: &Obligation) -> Vec<u8> {
+    obligation
+        .deposits
+        .iter()
+        .chain(obligation.borrows.iter())
+        .map(|p| p.asset_id)
+        .collect()
+}
+
+// ========== TOKEN CUSTODY ==========
+//
+// Assets added by mint are custodied in a program vault per mint: deposits
+// and repayments pull tokens from the owner's token account, withdrawals and
+// borrows push them out. The vault balance is the asset's available
+// liquidity. Manually numbered assets (default mint) stay bookkeeping-only,
+// and an obligation holds either kind but never both: bookkeeping deposits
+// move no tokens, so they must not back borrows paid out of a vault.
+//
+// Vaults only take the SPL Token program: Token-2022 transfer fees and hooks
+// would let the vault receive less than the amount credited to positions.
+
+/// Vault token account for `mint`
+pub fn vault_address(mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"vault", mint.as_ref()], &crate::ID)
+}
+
+/// PDA that owns every vault
+pub fn vault_authority_address() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"vault_authority"], &crate::ID)
+}
+
+struct TokenAccounts<'a, 'info> {
+    mint: &'a InterfaceAccount<'info, Mint>,
+    vault: &'a InterfaceAccount<'info, TokenAccount>,
+    user_token_account: &'a InterfaceAccount<'info, TokenAccount>,
+    vault_authority: &'a UncheckedAccount<'info>,
+    token_program: &'a Program<'info, Token>,
+}
+
+/// Mint of a custodied asset, or `None` for bookkeeping-only assets
+fn custodied_mint(registry: &AssetRegistry, asset_id: u8) -> Result<Option<Pubkey>> {
+    let asset = registry
+        .assets
+        .iter()
+        .find(|a| a.id == asset_id)
+        .ok_or(ErrorCode::AssetNotFound)?;
+    Ok(Some(asset.mint).filter(|mint| *mint != Pubkey::default()))
+}
+
+/// Rejects obligations whose positions mix custodied and bookkeeping-only
+/// assets.
+fn check_custody(obligation: &Obligation, registry: &AssetRegistry) -> Result<()> {
+    let mut custodied = None;
+    for position in obligation.deposits.iter().chain(obligation.borrows.iter()) {
+        let is_custodied = custodied_mint(registry, position.asset_id)?.is_some();
+        if *custodied.get_or_insert(is_custodied) != is_custodied {
+            msg!(
+                "Asset {} custody does not match the obligation's other positions",
+                position.asset_id
+            );
+            return Err(ErrorCode::MixedCustody.into());
+        }
+    }
+    Ok(())
+}
+
+/// Returns the optional token accounts, checked against `mint`'s vault.
+fn checked_token_accounts<'a, 'info>(
+    mint: Option<&'a InterfaceAccount<'info, Mint>>,
+    vault: Option<&'a InterfaceAccount<'info, TokenAccount>>,
+    user_token_account: Option<&'a InterfaceAccount<'info, TokenAccount>>,
+    vault_authority: Option<&'a UncheckedAccount<'info>>,
+    token_program: Option<&'a Program<'info, Token>>,
+    expected_mint: Pubkey,
+) -> Result<TokenAccounts<'a, 'info>> {
+    let token_accounts = match (
+        mint,
+        vault,
+        user_token_account,
+        vault_authority,
+        token_program,
+    ) {
+        (
+            Some(mint),
+            Some(vault),
+            Some(user_token_account),
+            Some(vault_authority),
+            Some(token_program),
+        ) => TokenAccounts {
+            mint,
+            vault,
+            user_token_account,
+            vault_authority,
+            token_program,
+        },
+        _ => return Err(ErrorCode::MissingTokenAccounts.into()),
+    };
+
+    if token_accounts.mint.key() != expected_mint {
+        return Err(ErrorCode::MintMismatch.into());
+    }
+    if token_accounts.vault.key() != vault_address(&expected_mint).0
+        || token_accounts.vault_authority.key() != vault_authority_address().0
+    {
+        return Err(ErrorCode::InvalidVault.into());
+    }
+    Ok(token_accounts)
+}
+
+fn token_accounts<'a, 'info>(
+    accounts: &'a ModifyObligation<'info>,
+    mint: Pubkey,
+) -> Result<TokenAccounts<'a, 'info>> {
+    checked_token_accounts(
+        accounts.mint.as_ref(),
+        accounts.vault.as_ref(),
+        accounts.user_token_account.as_ref(),
+        accounts.vault_authority.as_ref(),
+        accounts.token_program.as_ref(),
+        mint,
+    )
+}
+
+/// Moves `amount` from the user's token account into the vault, signed by
+/// `user`.
+fn pull_tokens<'info>(
+    tokens: &TokenAccounts<'_, 'info>,
+    user: AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    transfer_checked(
+        CpiContext::new(
+            tokens.token_program.to_account_info(),
+            TransferChecked {
+                from: tokens.user_token_account.to_account_info(),
+                mint: tokens.mint.to_account_info(),
+                to: tokens.vault.to_account_info(),
+                authority: user,
+            },
+        ),
+        amount,
+        tokens.mint.decimals,
+    )
+}
+
+/// Moves `amount` from the vault to the user's token account, signed by the
+/// vault authority.
+fn push_tokens(tokens: &TokenAccounts, amount: u64) -> Result<()> {
+    if tokens.vault.amount < amount {
+        msg!(
+            "Insufficient liquidity: vault holds {}, requested {}",
+            tokens.vault.amount,
+            amount
+        );
+        return Err(ErrorCode::InsufficientLiquidity.into());
+    }
+    let bump = [vault_authority_address().1];
+    let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", &bump]];
+
+    transfer_checked(
+        CpiContext::new_with_signer(
+            tokens.token_program.to_account_info(),
+            TransferChecked {
+                from: tokens.vault.to_account_info(),
+                mint: tokens.mint.to_account_info(),
+                to: tokens.user_token_account.to_account_info(),
+                authority: tokens.vault_authority.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        amount,
+        tokens.mint.decimals,
+    )
+}
+
+/// Moves `amount` of a custodied asset from the owner's token account into
+/// its vault.
+fn transfer_to_vault(accounts: &ModifyObligation, asset_id: u8, amount: u64) -> Result<()> {
+    match custodied_mint(&accounts.asset_registry, asset_id)? {
+        Some(mint) => pull_tokens(
+            &token_accounts(accounts, mint)?,
+            accounts.owner.to_account_info(),
+            amount,
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Moves `amount` of a custodied asset from its vault to the owner's token
+/// account.
+fn transfer_from_vault(accounts: &ModifyObligation, asset_id: u8, amount: u64) -> Result<()> {
+    match custodied_mint(&accounts.asset_registry, asset_id)? {
+        Some(mint) => push_tokens(&token_accounts(accounts, mint)?, amount),
+        None => Ok(()),
+    }
+}
+
+// ========== LIQUIDATION ==========
+
+struct Liquidation {
+    seized: u64,
+    health_before_x1000: u64,
+    health_after_x1000: u64,
+}
+
+/// Repays `amount` of an unhealthy obligation's borrow at `slot` and removes
+/// the collateral paid out for it. Token movement is left to the caller.
+fn apply_liquidation(
+    obligation: &mut Obligation,
+    registry: &mut AssetRegistry,
+    repay_asset_id: u8,
+    amount: u64,
+    collateral_asset_id: u8,
+    slot: u64,
+) -> Result<Liquidation> {
+    let owner = obligation.owner;
+    let sequence = obligation.sequence;
+    refresh_interest(obligation, registry, slot)?;
+    assert_operation_allowed(registry, repay_asset_id, Operation::Repay)
+        .map_err(|e| position_error(owner, sequence, e, repay_asset_id, amount, 0))?;
+
+    check_prices_fresh(obligation, registry, slot)?;
+    let health_before_x1000 = compute_health(obligation, registry)?;
+    if health_before_x1000 >= 1000 {
+        return Err(ErrorCode::ObligationHealthy.into());
+    }
+    // Gives the owner a window to react between partial liquidations
+    if let Some(last) = obligation.last_liquidation_slot {
+        let ready_slot = last.saturating_add(registry.liquidation_cooldown_slots);
+        if slot < ready_slot {
+            msg!(
+                "Liquidation throttled: current_slot={}, ready_slot={}",
+                slot,
+                ready_slot
+            );
+            return Err(ErrorCode::LiquidationThrottled.into());
+        }
+    }
+    check_seize_order(obligation, collateral_asset_id)?;
+
+    let borrowed = obligation
+        .borrows
+        .iter()
+        .find(|p| p.asset_id == repay_asset_id)
+        .ok_or_else(|| {
+            position_error(
+                owner,
+                sequence,
+                ErrorCode::BorrowNotFound,
+                repay_asset_id,
+                amount,
+                0,
+            )
+        })?
+        .amount;
+    // Rounds up so the last unit of a borrow can always be liquidated
+    let max_repay = mul_div(borrowed, registry.close_factor_bps as u64, 10_000, DEBT)
+        .ok_or(ErrorCode::MathOverflow)?;
+    if amount == 0 || amount > max_repay {
+        return Err(position_error(
+            owner,
+            sequence,
+            ErrorCode::CloseFactorExceeded,
+            repay_asset_id,
+            amount,
+            max_repay,
+        ));
+    }
+
+    let seized = seize_amount(registry, repay_asset_id, amount, collateral_asset_id)?;
+    let deposited = obligation
+        .deposits
+        .iter()
+        .find(|p| p.asset_id == collateral_asset_id)
+        .ok_or_else(|| {
+            position_error(
+                owner,
+                sequence,
+                ErrorCode::DepositNotFound,
+                collateral_asset_id,
+                seized,
+                0,
+            )
+        })?
+        .amount;
+    if seized > deposited {
+        return Err(position_error(
+            owner,
+            sequence,
+            ErrorCode::InsufficientDeposit,
+            collateral_asset_id,
+            seized,
+            deposited,
+        ));
+    }
+
+    decrease_position(
+        &mut obligation.borrows,
+        repay_asset_id,
+        amount,
+        ErrorCode::BorrowNotFound,
+        ErrorCode::InsufficientBorrow,
+    )?;
+    if !obligation
+        .borrows
+        .iter()
+        .any(|p| p.asset_id == repay_asset_id)
+    {
+        track_open_position(registry, repay_asset_id, false)?;
+    }
+    track_open_interest(registry, obligation, repay_asset_id, amount, false)?;
+    track_reserve(registry, repay_asset_id, Operation::Repay, amount, slot)?;
+
+    decrease_position(
+        &mut obligation.deposits,
+        collateral_asset_id,
+        seized,
+        ErrorCode::DepositNotFound,
+        ErrorCode::InsufficientDeposit,
+    )?;
+    if !obligation
+        .deposits
+        .iter()
+        .any(|p| p.asset_id == collateral_asset_id)
+    {
+        track_open_position(registry, collateral_asset_id, false)?;
+    }
+    track_reserve(
+        registry,
+        collateral_asset_id,
+        Operation::Withdraw,
+        seized,
+        slot,
+    )?;
+
+    obligation.last_liquidation_slot = Some(slot);
+
+    Ok(Liquidation {
+        seized,
+        health_before_x1000,
+        health_after_x1000: compute_health(obligation, registry)?,
+    })
+}
+
+/// Collateral worth `repay_amount` of the repaid asset plus the liquidation
+/// bonus, rounded down.
+fn seize_amount(
+    registry: &AssetRegistry,
+    repay_asset_id: u8,
+    repay_amount: u64,
+    collateral_asset_id: u8,
+) -> Result<u64> {
+    let price_of = |id: u8| {
+        registry
+            .assets
+            .iter()
+            .find(|a| a.id == id)
+            .map(|a| a.price)
+            .ok_or(ErrorCode::AssetNotFound)
+    };
+
+    let repay_value = repay_amount
+        .checked_mul(price_of(repay_asset_id)?)
+        .ok_or(ErrorCode::MathOverflow)?;
+    let seize_value = mul_div(
+        repay_value,
+        10_000 + registry.liquidation_bonus_bps as u64,
+        10_000,
+        COLLATERAL,
+    )
+    .ok_or(ErrorCode::MathOverflow)?;
+    let seized = math::div(seize_value, price_of(collateral_asset_id)?, COLLATERAL)
+        .ok_or(ErrorCode::InvalidPrice)?;
+    Ok(seized)
+}
+
+/// Collateral is seized in the owner's seize order; assets left out of it
+/// can only be seized once every listed asset is gone.
+fn check_seize_order(obligation: &Obligation, collateral_asset_id: u8) -> Result<()> {
+    let next = obligation
+        .seize_order
+        .iter()
+        .find(|id| obligation.deposits.iter().any(|p| p.asset_id == **id));
+    match next {
+        Some(&next) if next != collateral_asset_id => {
+            msg!(
+                "Seize order requires asset {} before asset {}",
+                next,
+                collateral_asset_id
+            );
+            Err(ErrorCode::SeizeOrderViolation.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// ========== SNAPSHOT DIFF ==========
+
+fn log_position_diff(kind: &str, before: &[Position], after: &[Position]) {
+    for old in before {
+        match after.iter().find(|p| p.asset_id == old.asset_id) {
+            None => msg!(
+                "  {} removed: asset_id={}, amount={}",
+                kind,
+                old.asset_id,
+                old.amount
+            ),
+            Some(new) if new.amount != old.amount => msg!(
+                "  {} changed: asset_id={}, amount {} -> {}",
+                kind,
+                old.asset_id,
+                old.amount,
+                new.amount
+            ),
+            Some(_) => {}
+        }
+    }
+    for new in after {
+        if !before.iter().any(|p| p.asset_id == new.asset_id) {
+            msg!(
+                "  {} added: asset_id={}, amount={}",
+                kind,
+                new.asset_id,
+                new.amount
+            );
+        }
+    }
+}
+
+// ========== MARGINAL HEALTH IMPACT ==========
+
+/// Health change from applying `operation` for `amount` of `asset_id` to a
+/// copy of the obligation. Market policy and position minimums are not
+/// checked, so clients can rank corrective actions by efficiency.
+pub fn marginal_health_impact(
+    obligation: &Obligation,
+    registry: &AssetRegistry,
+    operation: Operation,
+    asset_id: u8,
+    amount: u64,
+) -> Result<HealthChange> {
+    let before = compute_health(obligation, registry)?;
+
+    let mut after = obligation.clone();
+    match operation {
+        Operation::Deposit => increase_position(&mut after.deposits, asset_id, amount)?,
+        Operation::Borrow => increase_position(&mut after.borrows, asset_id, amount)?,
+        Operation::Withdraw => decrease_position(
+            &mut after.deposits,
+            asset_id,
+            amount,
+            ErrorCode::DepositNotFound,
+            ErrorCode::InsufficientDeposit,
+        )?,
+        Operation::Repay => decrease_position(
+            &mut after.borrows,
+            asset_id,
+            amount,
+            ErrorCode::BorrowNotFound,
+            ErrorCode::InsufficientBorrow,
+        )?,
+    }
+
+    Ok(HealthChange::new(before, compute_health(&after, registry)?))
+}
+
+fn increase_position(positions: &mut Vec<Position>, asset_id: u8, amount: u64) -> Result<()> {
+    match positions.iter_mut().find(|p| p.asset_id == asset_id) {
+        Some(position) => {
+            position.amount = position
+                .amount
+                .checked_add(amount)
+                .ok_or(ErrorCode::MathOverflow)?
+        }
+        None => positions.push(Position {
+            asset_id,
+            amount,
+            borrow_index: 0,
+        }),
+    }
+    Ok(())
+}
+
+fn decrease_position(
+    positions: &mut Vec<Position>,
+    asset_id: u8,
+    amount: u64,
+    not_found: ErrorCode,
+    insufficient: ErrorCode,
+) -> Result<()> {
+    let position = positions
+        .iter_mut()
+        .find(|p| p.asset_id == asset_id)
+        .ok_or(not_found)?;
+    position.amount = position.amount.checked_sub(amount).ok_or(insufficient)?;
+    if position.amount == 0 {
+        positions.retain(|p| p.asset_id != asset_id);
+    }
+    Ok(())
+}
+
+// ========== FEATURE GATES ==========
+
+fn require_feature(enabled: bool, name: &str) -> Result<()> {
+    if !enabled {
+        msg!("Feature disabled: {}", name);
+        return Err(ErrorCode::FeatureDisabled.into());
+    }
+    Ok(())
+}
+
+// ========== CO-SIGNERS ==========
+
+/// Requires the obligation's threshold of distinct signatures among the owner
+/// (already checked by the account constraints) and its co-signers.
+fn require_co_signers(obligation: &Obligation, signers: &[AccountInfo]) -> Result<()> {
+    if obligation.threshold <= 1 {
+        return Ok(());
+    }
+
+    let mut approvals = 1usize;
+    for co_signer in &obligation.co_signers {
+        if signers
+            .iter()
+            .any(|info| info.is_signer && info.key == co_signer)
+        {
+            approvals += 1;
+        }
+    }
+
+    if approvals < obligation.threshold as usize {
+        msg!(
+            "Co-signer approvals: {} of {} required",
+            approvals,
+            obligation.threshold
+        );
+        return Err(ErrorCode::InsufficientCoSigners.into());
+    }
+    Ok(())
+}
+
+// ========== HEALTH DELTA ==========
+
+fn report_health_change(
+    owner: Pubkey,
+    sequence: u64,
+    before_x1000: u64,
+    after_x1000: u64,
+) -> HealthChange {
+    let change = HealthChange::new(before_x1000, after_x1000);
+    msg!(
+        "Health x1000: {} -> {} (delta {})",
+        before_x1000,
+        after_x1000,
+        change.delta_x1000
+    );
+    emit!(HealthChanged {
+        owner,
+        sequence,
+        before_x1000,
+        after_x1000,
+        delta_x1000: change.delta_x1000,
+    });
+    change
+}
+
+// ========== HEALTH CHECK FUNCTION ==========
+
+/// Stores the registry prices behind the health decision just made, so the
+/// obligation itself answers which prices were used and when.
+fn record_health_prices(obligation: &mut Obligation, registry: &AssetRegistry) -> Result<()> {
+    let mut prices: Vec<PriceUsed> = Vec::new();
+    for position in obligation.deposits.iter().chain(obligation.borrows.iter()) {
+        if prices.iter().any(|p| p.asset_id == position.asset_id) {
+            continue;
+        }
+        let asset = registry
+            .assets
+            .iter()
+            .find(|a| a.id == position.asset_id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+        prices.push(PriceUsed {
+            asset_id: asset.id,
+            price: asset.price,
+        });
+    }
+
+    obligation.last_health_prices = prices;
+    obligation.last_health_slot = Clock::get()?.slot;
+    Ok(())
+}
+
+/// Rejects risk-increasing decisions on a price older than its asset's price
+/// age window. Deposits and repayments skip it, like the strict health check,
+/// so a stale oracle never traps an owner who wants to reduce risk.
+fn check_prices_fresh(obligation: &Obligation, registry: &AssetRegistry, slot: u64) -> Result<()> {
+    for position in obligation.deposits.iter().chain(obligation.borrows.iter()) {
+        let asset = registry
+            .assets
+            .iter()
+            .find(|a| a.id == position.asset_id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+        if oracle::is_stale(asset.price_slot, slot, asset.max_price_age_slots) {
+            msg!(
+                "Asset {} price from slot {} is older than {} slots",
+                asset.id,
+                asset.price_slot,
+                asset.max_price_age_slots
+            );
+            return Err(ErrorCode::StaleOraclePrice.into());
+        }
+    }
+    Ok(())
+}
+
+/// Rejects unhealthy obligations and returns the health score (x1000).
+fn perform_health_check(obligation: &Obligation, registry: &AssetRegistry) -> Result<u64> {
+    msg!("=== HEALTH CHECK START ===");
+
+    let final_health_score_x1000 = compute_health(obligation, registry)?;
+
+    // If no borrows, obligation is healthy by default
+    if final_health_score_x1000 == u64::MAX {
+        msg!("Health: OK (no borrows)");
+        return Ok(u64::MAX);
+    }
+
+    // Check if healthy (health score should be >= 1000 for 1.0 or 100% collateralization)
+    if final_health_score_x1000 < 1000 {
+        msg!(
+            "⚠️ WARNING: Health score {}.{} is below 1.0 - Position at risk!",
+            final_health_score_x1000 / 1000,
+            final_health_score_x1000 % 1000
+        );
+        return Err(ErrorCode::Unhealthy.into());
+    } else {
+        msg!(
+            "✓ Health check PASSED - Score: {}.{}",
+            final_health_score_x1000 / 1000,
+            final_health_score_x1000 % 1000
+        );
+    }
+
+    emit_margin_call(obligation, registry, final_health_score_x1000)?;
+
+    msg!("=== HEALTH CHECK END ===");
+    Ok(final_health_score_x1000)
+}
+
+/// Emits a MarginCall when `health_x1000` is healthy but inside the warning
+/// band, to notify the owner before liquidation is possible.
+fn emit_margin_call(
+    obligation: &Obligation,
+    registry: &AssetRegistry,
+    health_x1000: u64,
+) -> Result<()> {
+    if health_x1000 < 1000 || health_x1000 >= registry.warning_health_x1000 {
+        return Ok(());
+    }
+
+    let total_borrow_value = total_value(&obligation.borrows, registry)?;
+    // Extra risk-weighted collateral needed to get back to the warning level
+    let shortfall = mul_div(
+        registry.warning_health_x1000 - health_x1000,
+        total_borrow_value,
+        1000,
+        DEBT,
+    )
+    .unwrap_or(u64::MAX);
+
+    msg!(
+        "MARGIN CALL: health x1000={}, warning x1000={}, shortfall={}",
+        health_x1000,
+        registry.warning_health_x1000,
+        shortfall
+    );
+    emit!(MarginCall {
+        owner: obligation.owner,
+        sequence: obligation.sequence,
+        health_x1000,
+        warning_health_x1000: registry.warning_health_x1000,
+        shortfall,
+    });
+    Ok(())
+}
+
+/// Health check for risk-reducing operations (deposits and repayments). An
+/// unhealthy obligation may still improve without getting back above 1.0 in
+/// one step, so only a result that is both unhealthy and lower than
+/// `health_before_x1000` is rejected.
+fn check_health_not_reduced(
+    obligation: &Obligation,
+    registry: &AssetRegistry,
+    health_before_x1000: u64,
+) -> Result<u64> {
+    let health_x1000 = compute_health(obligation, registry)?;
+    if health_x1000 < 1000 && health_x1000 >= health_before_x1000 {
+        msg!(
+            "Health x1000 {} still below 1.0, up from {}",
+            health_x1000,
+            health_before_x1000
+        );
+        return Ok(health_x1000);
+    }
+    perform_health_check(obligation, registry)
+}
+
+/// Returns the health score scaled by 1000 (1000 = 1.0), or `u64::MAX` when
+/// the obligation has no borrows.
+fn compute_health(obligation: &Obligation, registry: &AssetRegistry) -> Result<u64> {
+    msg!(
+        "Deposits: {}, Borrows: {}",
+        obligation.deposits.len(),
+        obligation.borrows.len()
+    );
+
+    if obligation.borrows.is_empty() {
+        return Ok(u64::MAX);
+    }
+
+    // Calculate total deposit and borrow values
+    let mut deposit_values: Vec<(u8, u64)> = Vec::new(); // (asset_id, value)
+    let mut borrow_values: Vec<(u8, u64)> = Vec::new(); // (asset_id, value)
+    let mut total_deposit_value = 0u64;
+    let mut total_borrow_value = 0u64;
+
+    // Calculate deposit values
+    for deposit in &obligation.deposits {
+        let asset = registry
+            .assets
+            .iter()
+            .find(|a| a.id == deposit.asset_id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+
+        // Deposits count at their LTV; collateral-disabled ones carry no weight
+        let value = if asset.collateral_enabled {
+            mul_div(
+                deposit.amount.saturating_mul(asset.price),
+                asset.ltv_bps as u64,
+                AssetInfo::FULL_LTV_BPS as u64,
+                COLLATERAL,
+            )
+            .unwrap_or(0)
+        } else {
+            0
+        };
+        deposit_values.push((deposit.asset_id, value));
+        total_deposit_value = total_deposit_value.saturating_add(value);
+
+        msg!(
+            "Deposit: id={}, amount={}, price={}, value={}",
+            deposit.asset_id,
+            deposit.amount,
+            asset.price,
+            value
+        );
+    }
+
+    // Calculate borrow values
+    for borrow in &obligation.borrows {
+        let asset = registry
+            .assets
+            .iter()
+            .find(|a| a.id == borrow.asset_id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+
+        let value = borrow.amount.saturating_mul(asset.price);
+        borrow_values.push((borrow.asset_id, value));
+        total_borrow_value = total_borrow_value.saturating_add(value);
+
+        msg!(
+            "Borrow: id={}, amount={}, price={}, value={}",
+            borrow.asset_id,
+            borrow.amount,
+            asset.price,
+            value
+        );
+    }
+
+    msg!("Total deposit value: {}", total_deposit_value);
+    msg!("Total borrow value: {}", total_borrow_value);
+
+    // Complex health score calculation
+    // Health = Sum(deposit_i * Sum(borrow_share_j * risk_factor_ij)) / total_borrow
+    let mut weighted_health_score = 0u64;
+
+    // For each deposit
+    for (deposit_id, deposit_value) in &deposit_values {
+        msg!("Processing deposit {}: value={}", deposit_id, deposit_value);
+        let mut deposit_risk_sum = 0u64;
+
+        // For each borrow
+        for (borrow_id, borrow_value) in &borrow_values {
+            // Find risk parameter for this deposit-borrow pair
+            let risk_param = registry.risk_params.iter().find(|p| {
+                (p.asset_id_a == *deposit_id && p.asset_id_b == *borrow_id)
+                    || (p.asset_id_a == *borrow_id && p.asset_id_b == *deposit_id)
+            });
+
+            let risk_level = if let Some(param) = risk_param {
+                param.risk_level as u64
+            } else if obligation.mode == MarginMode::Isolated {
+                // Isolated pairs must be explicitly configured
+                msg!(
+                    "  No risk param for isolated pair {}-{}",
+                    deposit_id,
+                    borrow_id
+                );
+                return Err(ErrorCode::MissingRiskParam.into());
+            } else {
+                // Default risk level if pair not found
+                msg!(
+                    "  Warning: No risk param for pair {}-{}, using default 50",
+                    deposit_id,
+                    borrow_id
+                );
+                50
+            };
+
+            // Calculate borrow share (scaled by 100 for precision)
+            let borrow_share =
+                mul_div(*borrow_value, 100, total_borrow_value, COLLATERAL).unwrap_or(0);
+
+            // Add to deposit risk sum: borrow_share * risk_level
+            let risk_contribution = borrow_share.saturating_mul(risk_level);
+            deposit_risk_sum = deposit_risk_sum.saturating_add(risk_contribution);
+
+            msg!(
+                "  Pair {}-{}: borrow_value={}, share={}%, risk={}, contribution={}",
+                deposit_id,
+                borrow_id,
+                borrow_value,
+                borrow_share,
+                risk_level,
+                risk_contribution
+            );
+        }
+
+        // Multiply deposit value by its weighted risk (divide by 100 to adjust for scaling)
+        let weighted_deposit_value =
+            mul_div(*deposit_value, deposit_risk_sum, 100, COLLATERAL).unwrap_or(0);
+
+        weighted_health_score = weighted_health_score.saturating_add(weighted_deposit_value);
+
+        msg!(
+            "  Deposit {} total: risk_sum={}, weighted_value={}",
+            deposit_id,
+            deposit_risk_sum,
+            weighted_deposit_value
+        );
+    }
+
+    // Final health score calculation
+    // We have: weighted_health_score (already divided by 100 once)
+    // Need to divide by total_borrow_value
+    // But we want to keep decimal precision, so multiply by 1000 first
+    // x1000 for precision, /100 for the risk scaling
+    let final_health_score_x1000 =
+        mul_div(weighted_health_score, 10, total_borrow_value, COLLATERAL).unwrap_or(0);
+
+    msg!("=== FINAL CALCULATION ===");
+    msg!("Weighted health score sum: {}", weighted_health_score);
+    msg!("Total borrow value: {}", total_borrow_value);
+    msg!("Health score x1000: {}", final_health_score_x1000);
+    msg!(
+        "Health score: {}.{}",
+        final_health_score_x1000 / 1000,
+        final_health_score_x1000 % 1000
+    );
+
+    Ok(final_health_score_x1000)
+}
+
+// ========== LEVERAGE CHECK ==========
+
+fn total_value(positions: &[Position], registry: &AssetRegistry) -> Result<u64> {
+    let mut total = 0u64;
+    for position in positions {
+        let asset = registry
+            .assets
+            .iter()
+            .find(|a| a.id == position.asset_id)
+            .ok_or(ErrorCode::AssetNotFound)?;
+        total = total.saturating_add(position.amount.saturating_mul(asset.price));
+    }
+    Ok(total)
+}
+
+fn check_leverage(obligation: &Obligation, registry: &AssetRegistry) -> Result<()> {
+    // Cap disabled or nothing borrowed
+    if registry.max_leverage_bps == 0 || obligation.borrows.is_empty() {
+        return Ok(());
+    }
+
+    let total_deposit_value = total_value(&obligation.deposits, registry)?;
+    let total_borrow_value = total_value(&obligation.borrows, registry)?;
+
+    // Leverage = borrows / (deposits - borrows); no equity means unbounded leverage
+    let equity = total_deposit_value.saturating_sub(total_borrow_value);
+    let leverage_bps = mul_div(total_borrow_value, 10_000, equity, DEBT).unwrap_or(u64::MAX);
+
+    msg!(
+        "Leverage: {} bps (max {} bps)",
+        leverage_bps,
+        registry.max_leverage_bps
+    );
+
+    if leverage_bps > registry.max_leverage_bps as u64 {
+        return Err(ErrorCode::LeverageTooHigh.into());
+    }
+
+    Ok(())
+}
+
+// ========== CREDIT LIMIT ==========
+
+fn check_credit_limit(obligation: &Obligation, registry: &AssetRegistry) -> Result<()> {
+    if obligation.credit_limit == 0 {
+        return Ok(());
+    }
+
+    let borrow_value = total_value(&obligation.borrows, registry)?;
+    if borrow_value > obligation.credit_limit {
+        msg!(
+            "Credit limit exceeded: borrow value {} > limit {}",
+            borrow_value,
+            obligation.credit_limit
+        );
+        emit!(CreditLimitExceeded {
+            owner: obligation.owner,
+            sequence: obligation.sequence,
+            credit_limit: obligation.credit_limit,
+            borrow_value,
+        });
+        return Err(ErrorCode::CreditLimitExceeded.into());
+    }
+    Ok(())
+}
+
+// ========== VALUE CONVERSION ==========
+
+/// Converts a value into a token amount at the registry price, rounding down so
+/// neither borrows nor repayments exceed the requested value.
+fn value_to_amount(registry: &AssetRegistry, asset_id: u8, value: u64) -> Result<u64> {
+    let asset = registry
+        .assets
+        .iter()
+        .find(|a| a.id == asset_id)
+        .ok_or(ErrorCode::AssetNotFound)?;
+
+    let amount = math::div(value, asset.price, COLLATERAL).ok_or(ErrorCode::InvalidPrice)?;
+    if amount == 0 {
+        return Err(ErrorCode::AmountTooSmall.into());
+    }
+
+    Ok(amount)
+}
+
+/// Rejects execution when the price moved outside the caller's accepted band.
+fn check_price_band(
+    registry: &AssetRegistry,
+    asset_id: u8,
+    min_price: u64,
+    max_price: u64,
+) -> Result<()> {
+    let asset = registry
+        .assets
+        .iter()
+        .find(|a| a.id == asset_id)
+        .ok_or(ErrorCode::AssetNotFound)?;
+
+    if asset.price < min_price || asset.price > max_price {
+        msg!(
+            "Price {} for asset {} outside accepted band [{}, {}]",
+            asset.price,
+            asset_id,
+            min_price,
+            max_price
+        );
+        return Err(ErrorCode::PriceOutsideBand.into());
+    }
+
+    Ok(())
+}
+
+// ========== POSITION TRACKING ==========
+
+fn track_open_position(registry: &mut AssetRegistry, asset_id: u8, opened: bool) -> Result<()> {
+    let asset = registry
+        .assets
+        .iter_mut()
+        .find(|a| a.id == asset_id)
+        .ok_or(ErrorCode::AssetNotFound)?;
+
+    asset.open_positions = if opened {
+        asset.open_positions.checked_add(1)
+    } else {
+        asset.open_positions.checked_sub(1)
+    }
+    .ok_or(ErrorCode::MathOverflow)?;
+
+    Ok(())
+}
+
+// ========== INTEREST ACCRUAL ==========
+
+/// Accrues the asset's reserve to `slot` and returns its borrow index.
+fn accrue_reserve(registry: &mut AssetRegistry, asset_id: u8, slot: u64) -> Result<u128> {
+    let asset = registry
+        .assets
+        .iter_mut()
+        .find(|a| a.id == asset_id)
+        .ok_or(ErrorCode::AssetNotFound)?;
+    asset.reserve.accrue(&asset.rate_model, slot)?;
+    Ok(asset.reserve.borrow_index)
+}
+
+/// Accrues every reserve the obligation borrows from and settles its borrows
+/// at the current indexes, so the health math sees the debt owed now.
+fn refresh_interest(
+    obligation: &mut Obligation,
+    registry: &mut AssetRegistry,
+    slot: u64,
+) -> Result<()> {
+    for position in obligation.borrows.iter_mut() {
+        let index = accrue_reserve(registry, position.asset_id, slot)?;
+        position.amount =
+            owed(position.amount, position.borrow_index, index).ok_or(ErrorCode::MathOverflow)?;
+        position.borrow_index = index;
+    }
+    Ok(())
+}
+
+/// Moves the reserve's deposit and borrow totals by `amount`. The reserve is
+/// accrued to `slot` first, so the new utilization only prices interest from
+/// now on.
+fn track_reserve(
+    registry: &mut AssetRegistry,
+    asset_id: u8,
+    operation: Operation,
+    amount: u64,
+    slot: u64,
+) -> Result<()> {
+    accrue_reserve(registry, asset_id, slot)?;
+    let reserve = &mut registry
+        .assets
+        .iter_mut()
+        .find(|a| a.id == asset_id)
+        .ok_or(ErrorCode::AssetNotFound)?
+        .reserve;
+
+    // Positions opened before reserves were tracked were never counted
+    match operation {
+        Operation::Deposit => {
+            reserve.total_deposits = reserve
+                .total_deposits
+                .checked_add(amount)
+                .ok_or(ErrorCode::MathOverflow)?
+        }
+        Operation::Withdraw => {
+            reserve.total_deposits = reserve.total_deposits.saturating_sub(amount)
+        }
+        Operation::Borrow => {
+            reserve.total_borrows = reserve
+                .total_borrows
+                .checked_add(amount)
+                .ok_or(ErrorCode::MathOverflow)?
+        }
+        Operation::Repay => reserve.total_borrows = reserve.total_borrows.saturating_sub(amount),
+    }
+    Ok(())
+}
+
+// ========== OPEN INTEREST ==========
+
+/// Moves the open interest of an isolated obligation's collateral/borrow pair
+/// by `amount` of the borrowed asset, enforcing the pair's cap when it grows.
+///
+/// Cross obligations spread each borrow over all of their collateral, so
+/// their borrows cannot be attributed to a pair. They are refused any asset
+/// of a capped pair instead of bypassing the cap.
+fn track_open_interest(
+    registry: &mut AssetRegistry,
+    obligation: &Obligation,
+    borrow_id: u8,
+    amount: u64,
+    opened: bool,
+) -> std::result::Result<(), ErrorCode> {
+    if obligation.mode != MarginMode::Isolated {
+        let capped = registry.risk_params.iter().any(|p| {
+            p.max_open_interest > 0 && (p.asset_id_a == borrow_id || p.asset_id_b == borrow_id)
+        });
+        if opened && capped {
+            msg!(
+                "Asset {} is in a pair with an open interest cap; borrow it from an isolated obligation",
+                borrow_id
+            );
+            return Err(ErrorCode::CappedAssetRequiresIsolated);
+        }
+        return Ok(());
+    }
+    // Without collateral or a configured pair the health check rejects the
+    // borrow, so there is nothing to attribute
+    let deposit_id = match obligation.deposits.first() {
+        Some(deposit) => deposit.asset_id,
+        None => return Ok(()),
+    };
+
+    let price_of = |id: u8| {
+        registry
+            .assets
+            .iter()
+            .find(|a| a.id == id)
+            .map(|a| a.price)
+            .ok_or(ErrorCode::AssetNotFound)
+    };
+    let param = match registry.risk_params.iter().position(|p| {
+        (p.asset_id_a == deposit_id && p.asset_id_b == borrow_id)
+            || (p.asset_id_a == borrow_id && p.asset_id_b == deposit_id)
+    }) {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+    let price_a = price_of(registry.risk_params[param].asset_id_a)?;
+    let price_b = price_of(registry.risk_params[param].asset_id_b)?;
+
+    let param = &mut registry.risk_params[param];
+    let counter = if param.asset_id_a == borrow_id {
+        &mut param.open_interest_a
+    } else {
+        &mut param.open_interest_b
+    };
+
+    if !opened {
+        // Borrows made before the pair was configured were never counted
+        *counter = counter.saturating_sub(amount);
+        return Ok(());
+    }
+    *counter = counter.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+
+    if param.max_open_interest > 0 {
+        let value = param
+            .open_interest_a
+            .checked_mul(price_a)
+            .and_then(|a| {
+                param
+                    .open_interest_b
+                    .checked_mul(price_b)
+                    .and_then(|b| a.checked_add(b))
+            })
+            .ok_or(ErrorCode::MathOverflow)?;
+        if value > param.max_open_interest {
+            msg!(
+                "Pair {}-{} open interest {} exceeds cap {}",
+                param.asset_id_a,
+                param.asset_id_b,
+                value,
+                param.max_open_interest
+            );
+            return Err(ErrorCode::OpenInterestCapExceeded);
+        }
+    }
+
+    Ok(())
+}
+
+// ========== POSITION SIZE CHECK ==========
+
+fn check_position_minimum(
+    registry: &AssetRegistry,
+    position: &Position,
+    min_value: u64,
+    error: ErrorCode,
+) -> Result<()> {
+    // Closed positions and disabled minimums are always allowed
+    if position.amount == 0 || min_value == 0 {
+        return Ok(());
+    }
+
+    let asset = registry
+        .assets
+        .iter()
+        .find(|a| a.id == position.asset_id)
+        .ok_or(ErrorCode::AssetNotFound)?;
+
+    let value = position.amount.saturating_mul(asset.price);
+    if value < min_value {
+        msg!(
+            "Position too small: asset_id={}, value={}, minimum={}",
+            position.asset_id,
+            value,
+            min_value
+        );
+        return Err(error.into());
+    }
+
+    Ok(())
+}
+
+// ========== ERROR CONTEXT ==========
+
+/// Logs and emits the asset and amounts behind a failed position change, then
+/// returns the error so clients can explain it without re-simulating.
+fn position_error(
+    owner: Pubkey,
+    sequence: u64,
+    error: ErrorCode,
+    asset_id: u8,
+    requested: u64,
+    available: u64,
+) -> Error {
+    msg!(
+        "Error context: {:?}, asset_id={}, requested={}, available={}",
+        error,
+        asset_id,
+        requested,
+        available
+    );
+    emit!(PositionError {
+        owner,
+        sequence,
+        error_code: anchor_lang::error::ERROR_CODE_OFFSET + error as u32,
+        asset_id,
+        requested,
+        available,
+    });
+    error.into()
+}
+
+// ========== CONTEXTS ==========
+
+#[derive(Accounts)]
+pub struct InitializeAssetRegistry<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + AssetRegistry::INIT_SPACE,
+        seeds = [b"asset_registry"],
+        bump
+    )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + ObligationDirectory::INIT_SPACE,
+        seeds = [b"obligation_directory"],
+        bump
+    )]
+    pub obligation_directory: Account<'info, ObligationDirectory>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + MarketStats::INIT_SPACE,
+        seeds = [b"market_stats"],
+        bump
+    )]
+    pub market_stats: Account<'info, MarketStats>,
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + FeatureGates::INIT_SPACE,
+        seeds = [b"feature_gates"],
+        bump
+    )]
+    pub feature_gates: Account<'info, FeatureGates>,
+    pub authority: Signer<'info>,
+    /// Funds rent; may differ from the authority
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ManageAssetRegistry<'info> {
+    #[account(
+        mut,
+        seeds = [b"asset_registry"],
+        bump,
+        has_one = authority
+    )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePriceFromChainlink<'info> {
+    #[account(
+        mut,
+        seeds = [b"asset_registry"],
+        bump
+    )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+    /// CHECK: checked against the asset's configured feed before use
+    pub chainlink_feed: AccountInfo<'info>,
+    /// CHECK: checked against the Chainlink program ID before use
+    pub chainlink_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RefreshPrice<'info> {
+    #[account(
+        mut,
+        seeds = [b"asset_registry"],
+        bump
+    )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+}
+
+#[derive(Accounts)]
+pub struct ManageFeatureGates<'info> {
+    #[account(
+        mut,
+        seeds = [b"feature_gates"],
+        bump
+    )]
+    pub feature_gates: Account<'info, FeatureGates>,
+    #[account(
+        mut,
+        seeds = [b"asset_registry"],
+        bump,
+        has_one = authority
+    )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetCreditLimit<'info> {
+    #[account(
+        mut,
+        seeds = [b"asset_registry"],
+        bump,
+        has_one = authority
+    )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+    #[account(
+        mut,
+        seeds = [b"obligation", obligation.seed.as_ref()],
+        bump
+    )]
+    pub obligation: Account<'info, Obligation>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitVault<'info> {
+    #[account(
+        mut,
+        seeds = [b"asset_registry"],
+        bump,
+        has_one = authority
+    )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init,
+        payer = payer,
+        seeds = [b"vault", mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = vault_authority,
+        token::token_program = token_program
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+    /// CHECK: PDA that signs for every vault; holds no data
+    #[account(
+        seeds = [b"vault_authority"],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+    pub authority: Signer<'info>,
+    /// Funds rent; may differ from the authority
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SyncAssetDecimals<'info> {
+    #[account(
+        mut,
+        seeds = [b"asset_registry"],
+        bump,
+        has_one = authority
+    )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+    pub authority: Signer<'info>,
+    pub mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct ReadAssetRegistry<'info> {
+    #[account(
+        seeds = [b"asset_registry"],
+        bump
+    )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+}
+
+#[derive(Accounts)]
+pub struct InitObligation<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + Obligation::INIT_SPACE,
+        seeds = [b"obligation", owner.key().as_ref()],
+        bump
+    )]
+    pub obligation: Account<'info, Obligation>,
+    #[account(
+        mut,
+        seeds = [b"obligation_directory"],
+        bump
+    )]
+    pub obligation_directory: Account<'info, ObligationDirectory>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + ObligationPage::INIT_SPACE,
+        seeds = [
+            b"obligation_page".as_ref(),
+            &ObligationPage::page_for(obligation_directory.total_created).to_le_bytes()
+        ],
+        bump
+    )]
+    pub obligation_page: Account<'info, ObligationPage>,
+    #[account(
+        seeds = [b"feature_gates"],
+        bump
+    )]
+    pub feature_gates: Account<'info, FeatureGates>,
+    #[account(
+        seeds = [b"asset_registry"],
+        bump
+    )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + OwnerObligations::INIT_SPACE,
+        seeds = [b"owner_obligations", owner.key().as_ref()],
+        bump
+    )]
+    pub owner_obligations: Account<'info, OwnerObligations>,
+    pub owner: Signer<'info>,
+    /// Funds rent; may differ from the owner for sponsored transactions
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(start_index: u64)]
+pub struct ScanObligations<'info> {
+    #[account(
+        seeds = [b"asset_registry"],
+        bump
+    )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+    #[account(
+        seeds = [
+            b"obligation_page".as_ref(),
+            &ObligationPage::page_for(start_index).to_le_bytes()
+        ],
+        bump
+    )]
+    pub obligation_page: Account<'info, ObligationPage>,
 }
 
-// ========== CONTEXTS ==========
-
 #[derive(Accounts)]
-pub struct InitializeAssetRegistry<'info> {
+pub struct RefreshWorstObligation<'info> {
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + WorstObligation::INIT_SPACE,
+        seeds = [b"worst_obligation"],
+        bump
+    )]
+    pub worst_obligation: Account<'info, WorstObligation>,
+    #[account(
+        seeds = [b"obligation", obligation.seed.as_ref()],
+        bump
+    )]
+    pub obligation: Account<'info, Obligation>,
     #[account(
-        init,
-        payer = authority,
-        space = 8 + AssetRegistry::INIT_SPACE,
         seeds = [b"asset_registry"],
         bump
     )]
     pub asset_registry: Account<'info, AssetRegistry>,
     #[account(mut)]
-    pub authority: Signer<'info>,
+    pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct ManageAssetRegistry<'info> {
+#[instruction(mode: MarginMode, salt: [u8; 32])]
+pub struct InitPrivateObligation<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + Obligation::INIT_SPACE,
+        seeds = [b"obligation".as_ref(), &private_obligation_seed(&owner.key(), &salt)],
+        bump
+    )]
+    pub obligation: Account<'info, Obligation>,
     #[account(
         mut,
+        seeds = [b"obligation_directory"],
+        bump
+    )]
+    pub obligation_directory: Account<'info, ObligationDirectory>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + ObligationPage::INIT_SPACE,
+        seeds = [
+            b"obligation_page".as_ref(),
+            &ObligationPage::page_for(obligation_directory.total_created).to_le_bytes()
+        ],
+        bump
+    )]
+    pub obligation_page: Account<'info, ObligationPage>,
+    #[account(
+        seeds = [b"feature_gates"],
+        bump
+    )]
+    pub feature_gates: Account<'info, FeatureGates>,
+    #[account(
         seeds = [b"asset_registry"],
-        bump,
-        has_one = authority
+        bump
     )]
     pub asset_registry: Account<'info, AssetRegistry>,
-    pub authority: Signer<'info>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + OwnerObligations::INIT_SPACE,
+        seeds = [b"owner_obligations", owner.key().as_ref()],
+        bump
+    )]
+    pub owner_obligations: Account<'info, OwnerObligations>,
+    pub owner: Signer<'info>,
+    /// Funds rent; may differ from the owner for sponsored transactions
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct InitObligation<'info> {
+pub struct CloseObligation<'info> {
     #[account(
-        init,
-        payer = owner,
-        space = 8 + Obligation::INIT_SPACE,
-        seeds = [b"obligation", owner.key().as_ref()],
-        bump
+        mut,
+        close = owner,
+        seeds = [b"obligation", obligation.seed.as_ref()],
+        bump,
+        has_one = owner
     )]
     pub obligation: Account<'info, Obligation>,
+    #[account(
+        mut,
+        seeds = [b"obligation_directory"],
+        bump
+    )]
+    pub obligation_directory: Account<'info, ObligationDirectory>,
+    #[account(
+        mut,
+        seeds = [
+            b"obligation_page".as_ref(),
+            &ObligationPage::page_for(obligation.index).to_le_bytes()
+        ],
+        bump
+    )]
+    pub obligation_page: Account<'info, ObligationPage>,
+    #[account(
+        mut,
+        seeds = [b"owner_obligations", owner.key().as_ref()],
+        bump
+    )]
+    pub owner_obligations: Account<'info, OwnerObligations>,
+    /// Cleared when it records this obligation; pass it whenever it exists
+    #[account(
+        mut,
+        seeds = [b"worst_obligation"],
+        bump
+    )]
+    pub worst_obligation: Option<Account<'info, WorstObligation>>,
     #[account(mut)]
     pub owner: Signer<'info>,
-    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
 pub struct ModifyObligation<'info> {
     #[account(
         mut,
-        seeds = [b"obligation", owner.key().as_ref()],
+        seeds = [b"obligation", obligation.seed.as_ref()],
         bump,
         has_one = owner
     )]
     pub obligation: Account<'info, Obligation>,
     #[account(
+        mut,
         seeds = [b"asset_registry"],
         bump
     )]
     pub asset_registry: Account<'info, AssetRegistry>,
+    #[account(
+        mut,
+        seeds = [b"market_stats"],
+        bump
+    )]
+    pub market_stats: Account<'info, MarketStats>,
+    /// Authorizes only: the context allocates nothing, so the transaction
+    /// fee payer may be anyone
     pub owner: Signer<'info>,
+    // Token accounts, required only when the asset is custodied (has a mint)
+    pub mint: Option<InterfaceAccount<'info, Mint>>,
+    #[account(mut)]
+    pub vault: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub user_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    /// CHECK: checked against the vault authority PDA before use
+    pub vault_authority: Option<UncheckedAccount<'info>>,
+    pub token_program: Option<Program<'info, Token>>,
+}
+
+#[derive(Accounts)]
+pub struct Liquidate<'info> {
+    #[account(
+        mut,
+        seeds = [b"obligation", obligation.seed.as_ref()],
+        bump
+    )]
+    pub obligation: Account<'info, Obligation>,
+    #[account(
+        mut,
+        seeds = [b"asset_registry"],
+        bump
+    )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+    #[account(
+        mut,
+        seeds = [b"market_stats"],
+        bump
+    )]
+    pub market_stats: Account<'info, MarketStats>,
+    pub liquidator: Signer<'info>,
+    // Token accounts for the repaid asset, required only when it is custodied
+    pub repay_mint: Option<InterfaceAccount<'info, Mint>>,
+    #[account(mut)]
+    pub repay_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub liquidator_repay_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    // Token accounts for the seized collateral, required only when it is custodied
+    pub collateral_mint: Option<InterfaceAccount<'info, Mint>>,
+    #[account(mut)]
+    pub collateral_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub liquidator_collateral_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    /// CHECK: checked against the vault authority PDA before use
+    pub vault_authority: Option<UncheckedAccount<'info>>,
+    pub token_program: Option<Program<'info, Token>>,
 }
 
 #[derive(Accounts)]
@@ -579,10 +3748,27 @@ pub struct DebugReadData<'info> {
     )]
     pub asset_registry: Account<'info, AssetRegistry>,
     #[account(
-        seeds = [b"obligation", obligation.owner.as_ref()],
+        seeds = [b"obligation", obligation.seed.as_ref()],
+        bump
+    )]
+    pub obligation: Account<'info, Obligation>,
+}
+
+#[derive(Accounts)]
+pub struct DebugDiff<'info> {
+    #[account(
+        seeds = [b"asset_registry"],
         bump
     )]
+    pub asset_registry: Account<'info, AssetRegistry>,
+    #[account(
+        mut,
+        seeds = [b"obligation", obligation.seed.as_ref()],
+        bump,
+        has_one = owner
+    )]
     pub obligation: Account<'info, Obligation>,
+    pub owner: Signer<'info>,
 }
 
 // ========== ERROR CODES ==========
@@ -607,6 +3793,210 @@ pub enum ErrorCode {
     Unhealthy,
     #[msg("Math operation overflowed")]
     MathOverflow,
+    #[msg("Isolated obligations support a single deposit and borrow asset")]
+    IsolatedPairMismatch,
+    #[msg("Risk parameter is required for this pair")]
+    MissingRiskParam,
+    #[msg("Seize order contains duplicate or too many assets")]
+    InvalidSeizeOrder,
+    #[msg("Deposit value is below the market minimum")]
+    DepositBelowMinimum,
+    #[msg("Borrow value is below the market minimum")]
+    BorrowBelowMinimum,
+    #[msg("Obligation leverage exceeds the market cap")]
+    LeverageTooHigh,
+    #[msg("Warning threshold must be above 1.0 (1000)")]
+    InvalidWarningThreshold,
+    #[msg("Asset price must be non-zero")]
+    InvalidPrice,
+    #[msg("Value is too small to convert into a non-zero amount")]
+    AmountTooSmall,
+    #[msg("Asset price is outside the accepted band")]
+    PriceOutsideBand,
+    #[msg("Asset is deprecated and does not accept new positions")]
+    AssetDeprecated,
+    #[msg("Asset registry is full")]
+    RegistryFull,
+    #[msg("Mint account does not match the asset's registered mint")]
+    MintMismatch,
+    #[msg("Asset has open positions; pass force to override")]
+    AssetHasOpenPositions,
+    #[msg("Obligation still has deposits or borrows")]
+    ObligationNotEmpty,
+    #[msg("Obligation account does not match the directory entry")]
+    InvalidObligationAccount,
+    #[msg("Asset requires request_withdraw before withdrawing")]
+    WithdrawalCooldownRequired,
+    #[msg("No pending withdrawal for this obligation")]
+    NoPendingWithdrawal,
+    #[msg("Withdrawal cooldown has not elapsed")]
+    WithdrawalCooldownActive,
+    #[msg("Market is paused")]
+    MarketPaused,
+    #[msg("Borrow would exceed the open interest cap for this pair")]
+    OpenInterestCapExceeded,
+    #[msg("Registry admin nonce does not match the expected value")]
+    AdminNonceMismatch,
+    #[msg("Feature is disabled")]
+    FeatureDisabled,
+    #[msg("Co-signers must be distinct, exclude the owner and fit the threshold")]
+    InvalidCoSigners,
+    #[msg("Not enough co-signer approvals")]
+    InsufficientCoSigners,
+    #[msg("Borrow would exceed the obligation's credit limit")]
+    CreditLimitExceeded,
+    #[msg("Owner has reached the maximum number of obligations")]
+    TooManyObligations,
+    #[msg("LTV cannot exceed 10000 bps")]
+    InvalidLtv,
+    #[msg(
+        "Custodied assets need the mint, vault, token account, vault authority and token program"
+    )]
+    MissingTokenAccounts,
+    #[msg("Vault accounts do not match the asset's mint")]
+    InvalidVault,
+    #[msg("Queued feature gates are still timelocked")]
+    FeatureGateTimelockActive,
+    #[msg("No feature gates are queued")]
+    NoPendingFeatureGates,
+    #[msg("Asset is not accepted as collateral")]
+    CollateralDisabled,
+    #[msg("Asset is not borrowable")]
+    BorrowDisabled,
+    #[msg("Vault does not hold enough tokens")]
+    InsufficientLiquidity,
+    #[msg("Obligations cannot mix custodied and bookkeeping-only assets")]
+    MixedCustody,
+    #[msg("Obligation is healthy and cannot be liquidated")]
+    ObligationHealthy,
+    #[msg("Repay amount exceeds the close factor")]
+    CloseFactorExceeded,
+    #[msg("Collateral must be seized in the obligation's seize order")]
+    SeizeOrderViolation,
+    #[msg("Liquidation bonus cannot exceed 10000 bps and close factor must be 1-10000 bps")]
+    InvalidLiquidationParams,
+    #[msg("Optimal utilization must be 1-10000 bps")]
+    InvalidRateModel,
+    #[msg("Obligation was liquidated too recently")]
+    LiquidationThrottled,
+    #[msg("Minimum price cannot exceed the maximum price")]
+    InvalidPriceBounds,
+    #[msg("Asset is not priced by this oracle source")]
+    OracleSourceMismatch,
+    #[msg("Oracle accounts do not match the asset's configured source")]
+    InvalidOracleAccount,
+    #[msg("No oracle or recent cached price is available for the asset")]
+    MissingOraclePrice,
+    #[msg("Oracle price is older than the asset's price age window")]
+    StaleOraclePrice,
+    #[msg(
+        "Assets in a pair with an open interest cap can only be borrowed from isolated obligations"
+    )]
+    CappedAssetRequiresIsolated,
+}
+
+// ========== EVENTS ==========
+
+#[event]
+pub struct PositionError {
+    pub owner: Pubkey,
+    pub sequence: u64,
+    pub error_code: u32,
+    pub asset_id: u8,
+    pub requested: u64,
+    pub available: u64,
+}
+
+#[event]
+pub struct MarginCall {
+    pub owner: Pubkey,
+    pub sequence: u64,
+    pub health_x1000: u64,
+    pub warning_health_x1000: u64,
+    pub shortfall: u64,
+}
+
+#[event]
+pub struct RegistryReport {
+    pub assets: u8,
+    pub risk_params: u8,
+    pub issues: Vec<RegistryIssue>,
+}
+
+#[event]
+pub struct CreditLimitSet {
+    pub owner: Pubkey,
+    pub sequence: u64,
+    pub obligation: Pubkey,
+    pub credit_limit: u64,
+}
+
+#[event]
+pub struct CreditLimitExceeded {
+    pub owner: Pubkey,
+    pub sequence: u64,
+    pub credit_limit: u64,
+    pub borrow_value: u64,
+}
+
+#[event]
+pub struct HealthChanged {
+    pub owner: Pubkey,
+    pub sequence: u64,
+    pub before_x1000: u64,
+    pub after_x1000: u64,
+    pub delta_x1000: i64,
+}
+
+#[event]
+pub struct PriceOutOfBounds {
+    pub asset_id: u8,
+    pub price: u64,
+    pub min_price: u64,
+    pub max_price: u64,
+}
+
+#[event]
+pub struct Liquidated {
+    pub owner: Pubkey,
+    pub sequence: u64,
+    pub liquidator: Pubkey,
+    pub repay_asset_id: u8,
+    pub repay_amount: u64,
+    pub collateral_asset_id: u8,
+    pub seized_amount: u64,
+    pub health_before_x1000: u64,
+    pub health_after_x1000: u64,
+}
+
+/// Emitted by obligation instructions that have no more specific event, so
+/// every sequence number shows up in the event stream.
+#[event]
+pub struct ObligationUpdated {
+    pub owner: Pubkey,
+    pub sequence: u64,
+    pub update: ObligationUpdate,
+}
+
+#[derive(Debug, Clone, Copy, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub enum ObligationUpdate {
+    Deposited {
+        asset_id: u8,
+        amount: u64,
+    },
+    Repaid {
+        asset_id: u8,
+        amount: u64,
+    },
+    WithdrawalRequested {
+        asset_id: u8,
+        amount: u64,
+        ready_slot: u64,
+    },
+    SeizeOrderSet,
+    CoSignersSet {
+        threshold: u8,
+    },
 }
 
 // ========== DATA STRUCTURES ==========
@@ -619,6 +4009,68 @@ pub struct AssetRegistry {
     pub assets: Vec<AssetInfo>,
     #[max_len(50)]
     pub risk_params: Vec<PairRiskParam>,
+    /// Smallest value a non-empty deposit position may hold (0 = disabled)
+    pub min_deposit_value: u64,
+    /// Smallest value a non-empty borrow position may hold (0 = disabled)
+    pub min_borrow_value: u64,
+    /// Max ratio of borrow value to net equity in bps (0 = disabled)
+    pub max_leverage_bps: u32,
+    /// Health x1000 below which a MarginCall is emitted (0 = disabled)
+    pub warning_health_x1000: u64,
+    /// When set, only repayments are accepted
+    pub paused: bool,
+    /// Incremented by every authority instruction
+    pub admin_nonce: u64,
+    /// Max open obligations per owner (0 = unlimited)
+    pub max_obligations_per_owner: u8,
+    /// Extra collateral value paid to liquidators, in bps of the repaid value
+    pub liquidation_bonus_bps: u16,
+    /// Max share of a borrow repayable per liquidation, in bps
+    pub close_factor_bps: u16,
+    /// Slots after a liquidation before the obligation can be liquidated again
+    pub liquidation_cooldown_slots: u64,
+}
+
+impl AssetRegistry {
+    pub const MAX_ASSETS: usize = 20;
+    pub const MAX_RISK_PARAMS: usize = 50;
+    pub const DEFAULT_LIQUIDATION_BONUS_BPS: u16 = 500;
+    pub const DEFAULT_CLOSE_FACTOR_BPS: u16 = 5_000;
+
+    /// Empty registry with default risk parameters
+    pub fn new(authority: Pubkey) -> Self {
+        AssetRegistry {
+            authority,
+            assets: Vec::new(),
+            risk_params: Vec::new(),
+            min_deposit_value: 0,
+            min_borrow_value: 0,
+            max_leverage_bps: 0,
+            warning_health_x1000: 0,
+            paused: false,
+            admin_nonce: 0,
+            max_obligations_per_owner: 0,
+            liquidation_bonus_bps: AssetRegistry::DEFAULT_LIQUIDATION_BONUS_BPS,
+            close_factor_bps: AssetRegistry::DEFAULT_CLOSE_FACTOR_BPS,
+            liquidation_cooldown_slots: 0,
+        }
+    }
+
+    pub fn bump_admin_nonce(&mut self) -> Result<()> {
+        self.admin_nonce = self
+            .admin_nonce
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+}
+
+/// Portable copy of the registry contents, returned by `export_registry`
+/// and accepted back by `import_assets` on a fresh deployment.
+#[derive(Debug, Clone, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct RegistrySnapshot {
+    pub assets: Vec<AssetInfo>,
+    pub risk_params: Vec<PairRiskParam>,
 }
 
 #[derive(Debug, Clone, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, InitSpace)]
@@ -626,6 +4078,75 @@ pub struct AssetInfo {
     pub id: u8,
     pub price: u64,
     pub decimals: u8,
+    pub status: AssetStatus,
+    /// Token mint for assets added by mint; default for manually numbered assets
+    pub mint: Pubkey,
+    /// Number of obligation positions (deposits and borrows) holding the asset
+    pub open_positions: u32,
+    /// Slots between request_withdraw and execute_withdraw (0 = direct withdrawals)
+    pub withdraw_cooldown_slots: u64,
+    /// Accepted as a deposit and counted by the health math
+    pub collateral_enabled: bool,
+    /// Accepted as a borrow
+    pub borrow_enabled: bool,
+    /// Share of a deposit's value that counts toward health, in bps
+    pub ltv_bps: u16,
+    pub reserve: Reserve,
+    pub rate_model: RateModel,
+    /// Lowest plausible price (0 = unbounded)
+    pub min_price: u64,
+    /// Highest plausible price (0 = unbounded)
+    pub max_price: u64,
+    pub oracle: OracleSource,
+    /// Tried when the primary oracle fails
+    pub fallback_oracle: Option<OracleSource>,
+    /// Slot the cached price was observed at
+    pub price_slot: u64,
+    /// Oldest price the fallback chain and health checks accept, in slots
+    /// (0 = no cached fallback and no age checks)
+    pub max_price_age_slots: u64,
+}
+
+impl AssetInfo {
+    pub const FULL_LTV_BPS: u16 = 10_000;
+
+    /// Active asset priced by the authority, with full LTV, no price bounds
+    /// and no interest
+    pub fn new(id: u8, price: u64, decimals: u8, mint: Pubkey) -> Self {
+        AssetInfo {
+            id,
+            price,
+            decimals,
+            status: AssetStatus::Active,
+            mint,
+            open_positions: 0,
+            withdraw_cooldown_slots: 0,
+            collateral_enabled: true,
+            borrow_enabled: true,
+            ltv_bps: AssetInfo::FULL_LTV_BPS,
+            reserve: Reserve::new(0),
+            rate_model: RateModel::ZERO,
+            min_price: 0,
+            max_price: 0,
+            oracle: OracleSource::Authority,
+            fallback_oracle: None,
+            price_slot: 0,
+            max_price_age_slots: 0,
+        }
+    }
+
+    pub fn price_within_bounds(&self, price: u64) -> bool {
+        price >= self.min_price && (self.max_price == 0 || price <= self.max_price)
+    }
+}
+
+#[derive(Debug, Clone, Copy, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, InitSpace)]
+pub enum AssetStatus {
+    /// Accepts new deposits and borrows
+    Active,
+    /// Still priced for health, repayment, withdrawal and liquidation, but
+    /// blocks new deposits and borrows
+    Deprecated,
 }
 
 #[derive(Debug, Clone, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, InitSpace)]
@@ -633,22 +4154,297 @@ pub struct PairRiskParam {
     pub asset_id_a: u8,
     pub asset_id_b: u8,
     pub risk_level: u8,
+    /// Amount of asset a borrowed by isolated obligations backed by asset b
+    pub open_interest_a: u64,
+    /// Amount of asset b borrowed by isolated obligations backed by asset a
+    pub open_interest_b: u64,
+    /// Cap on the open interest value of both sides combined; 0 means no cap.
+    /// While set, cross obligations cannot borrow either asset.
+    pub max_open_interest: u64,
 }
 
 #[derive(Debug, Clone, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, InitSpace)]
 pub struct Position {
     pub asset_id: u8,
     pub amount: u64,
+    /// Reserve borrow index the amount was last settled at (borrows only; 0
+    /// until first settled)
+    pub borrow_index: u128,
+}
+
+#[derive(Debug, Clone, Copy, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, InitSpace)]
+pub enum MarginMode {
+    /// All deposits back all borrows (default behavior)
+    Cross,
+    /// A single collateral/borrow pair with an explicit risk parameter
+    Isolated,
 }
 
 #[account]
 #[derive(InitSpace)]
 pub struct Obligation {
+    /// Stored in plaintext for private obligations too
     pub owner: Pubkey,
+    /// PDA seed: the owner key, or a salted owner hash for private obligations
+    pub seed: [u8; 32],
+    pub mode: MarginMode,
     #[max_len(11)]
     pub deposits: Vec<Position>,
     #[max_len(10)]
     pub borrows: Vec<Position>,
+    /// Collateral asset ids in the order liquidators should seize them
+    #[max_len(11)]
+    pub seize_order: Vec<u8>,
+    /// Position in the obligation directory pages
+    pub index: u64,
+    pub pending_withdrawal: Option<PendingWithdrawal>,
+    /// Registry prices used by the last health check, for audits
+    #[max_len(21)]
+    pub last_health_prices: Vec<PriceUsed>,
+    /// Slot of the last health check
+    pub last_health_slot: u64,
+    /// Additional signers sharing control of risk-increasing actions
+    #[max_len(5)]
+    pub co_signers: Vec<Pubkey>,
+    /// Signatures required among the owner and co-signers (0 or 1 = owner only)
+    pub threshold: u8,
+    /// Max total borrow value, assigned by the registry authority (0 = no limit)
+    pub credit_limit: u64,
+    /// Incremented by every instruction that changes the obligation and
+    /// carried on its events, so indexers can order updates and spot gaps
+    pub sequence: u64,
+    /// Slot of the last liquidation
+    pub last_liquidation_slot: Option<u64>,
+    /// Hash of the state recorded by the last `debug_diff` call. Debug
+    /// bookkeeping only, so it does not move `sequence`.
+    pub snapshot_hash: [u8; 32],
+    /// Slot of the last `debug_diff` call; 0 before the first one
+    pub snapshot_slot: u64,
+}
+
+#[derive(Debug, Clone, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, InitSpace)]
+pub struct PriceUsed {
+    pub asset_id: u8,
+    pub price: u64,
+}
+
+#[derive(Debug, Clone, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, InitSpace)]
+pub struct PendingWithdrawal {
+    pub asset_id: u8,
+    pub amount: u64,
+    pub ready_slot: u64,
+}
+
+impl Obligation {
+    pub const MAX_SEIZE_ORDER: usize = 11;
+    pub const MAX_CO_SIGNERS: usize = 5;
+
+    /// Obligation with no positions, owner-only control and no credit limit
+    pub fn new(owner: Pubkey, seed: [u8; 32], mode: MarginMode) -> Self {
+        Obligation {
+            owner,
+            seed,
+            mode,
+            deposits: Vec::new(),
+            borrows: Vec::new(),
+            seize_order: Vec::new(),
+            index: 0,
+            pending_withdrawal: None,
+            last_health_prices: Vec::new(),
+            last_health_slot: 0,
+            co_signers: Vec::new(),
+            threshold: 0,
+            credit_limit: 0,
+            sequence: 0,
+            last_liquidation_slot: None,
+            snapshot_hash: [0u8; 32],
+            snapshot_slot: 0,
+        }
+    }
+
+    pub fn bump_sequence(&mut self) -> Result<()> {
+        self.sequence = self
+            .sequence
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+}
+
+/// Per-operation instruction counters, giving on-chain telemetry without an
+/// indexer.
+#[account]
+#[derive(InitSpace)]
+pub struct MarketStats {
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub borrows: u64,
+    pub repays: u64,
+    pub liquidations: u64,
+}
+
+impl MarketStats {
+    pub fn record(&mut self, operation: Operation) -> Result<()> {
+        let counter = match operation {
+            Operation::Deposit => &mut self.deposits,
+            Operation::Withdraw => &mut self.withdrawals,
+            Operation::Borrow => &mut self.borrows,
+            Operation::Repay => &mut self.repays,
+        };
+        *counter = counter.checked_add(1).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Liquidations are counted on their own, not as the repay and
+    /// withdrawal they perform.
+    pub fn record_liquidation(&mut self) -> Result<()> {
+        self.liquidations = self
+            .liquidations
+            .checked_add(1)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+}
+
+/// Named switches for optional subsystems. Changes are queued by the
+/// authority and only take effect after a timelock.
+#[account]
+#[derive(InitSpace)]
+pub struct FeatureGates {
+    pub active: GateSet,
+    pub pending: Option<GateSet>,
+    /// Slot from which `pending` may be applied
+    pub ready_slot: u64,
+}
+
+impl FeatureGates {
+    /// About an hour of slots
+    pub const TIMELOCK_SLOTS: u64 = 9_000;
+}
+
+#[derive(Debug, Clone, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, InitSpace)]
+pub struct GateSet {
+    /// `init_obligation` and `init_private_obligation` accept isolated mode
+    pub isolated_margin: bool,
+    /// `init_private_obligation` is available; it hides the address
+    /// derivation, not the owner
+    pub private_obligations: bool,
+}
+
+/// Open obligation count for one owner, public and private alike. It reveals
+/// how many obligations a wallet has, private ones included.
+#[account]
+#[derive(InitSpace)]
+pub struct OwnerObligations {
+    pub open: u32,
+}
+
+/// Global obligation counters, letting clients report open positions without
+/// a program-account scan.
+#[account]
+#[derive(InitSpace)]
+pub struct ObligationDirectory {
+    pub open_obligations: u64,
+    /// Next obligation index; owners are appended to pages in this order
+    pub total_created: u64,
+}
+
+/// Fixed-size page of obligation PDA seeds by index (the owner key for
+/// regular obligations). Closed obligations leave zeroes in their slot.
+#[account]
+#[derive(InitSpace)]
+pub struct ObligationPage {
+    #[max_len(32)]
+    pub seeds: Vec<[u8; 32]>,
+}
+
+impl ObligationPage {
+    pub const CAPACITY: usize = 32;
+
+    pub fn page_for(index: u64) -> u64 {
+        index / Self::CAPACITY as u64
+    }
+}
+
+/// Obligation state returned by `debug_diff`. Only its hash is stored, on
+/// the obligation; clients keep the snapshot and pass it to the next call.
+#[derive(Debug, Clone, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct ObligationSnapshot {
+    pub deposits: Vec<Position>,
+    pub borrows: Vec<Position>,
+    pub health_x1000: u64,
+    /// Slot the snapshot was taken at
+    pub slot: u64,
+}
+
+impl ObligationSnapshot {
+    /// Hash of the positions and health; the slot is left out so an
+    /// unchanged obligation keeps its hash
+    pub fn hash(&self) -> Result<[u8; 32]> {
+        let deposits = self.deposits.try_to_vec()?;
+        let borrows = self.borrows.try_to_vec()?;
+        Ok(hashv(&[&deposits, &borrows, &self.health_x1000.to_le_bytes()]).to_bytes())
+    }
+}
+
+/// Least healthy obligation seen by `refresh_worst_obligation`, giving an
+/// O(1) read of the riskiest known position.
+#[account]
+#[derive(InitSpace)]
+pub struct WorstObligation {
+    pub obligation: Pubkey,
+    /// `compute_health` scale (x1000, `u64::MAX` without borrows)
+    pub health_x1000: u64,
+    /// Slot of the last update
+    pub slot: u64,
+}
+
+impl WorstObligation {
+    /// About an hour of slots; an older record can be replaced by any
+    /// obligation
+    pub const MAX_AGE_SLOTS: u64 = 9_000;
+
+    /// Forgets the record, so the next refresh takes over
+    pub fn clear(&mut self) {
+        self.obligation = Pubkey::default();
+        self.health_x1000 = u64::MAX;
+        self.slot = 0;
+    }
+}
+
+/// Entry returned by `scan_obligations`; health uses the `compute_health`
+/// scale (x1000, `u64::MAX` without borrows).
+#[derive(Debug, Clone, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct ObligationHealth {
+    pub index: u64,
+    pub obligation: Pubkey,
+    pub health_x1000: u64,
+}
+
+impl ObligationHealth {
+    /// Keeps the serialized result under the 1024-byte return data limit
+    pub const MAX_PER_SCAN: usize = 16;
+}
+
+/// Health before and after a modify instruction, on the `compute_health`
+/// scale (x1000, `u64::MAX` without borrows). The delta saturates at the i64
+/// range, so opening or closing the last borrow reports an extreme value.
+#[derive(Debug, Clone, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub struct HealthChange {
+    pub before_x1000: u64,
+    pub after_x1000: u64,
+    pub delta_x1000: i64,
+}
+
+impl HealthChange {
+    pub fn new(before_x1000: u64, after_x1000: u64) -> Self {
+        let delta = after_x1000 as i128 - before_x1000 as i128;
+        HealthChange {
+            before_x1000,
+            after_x1000,
+            delta_x1000: delta.clamp(i64::MIN as i128, i64::MAX as i128) as i64,
+        }
+    }
 }
 
 #[derive(Accounts)]
@@ -0,0 +1,77 @@
+// ========== ROUNDING ==========
+//
+// Every division in the value pipeline states which way it rounds, always in
+// the protocol's favor:
+//   - collateral values and health scores round down
+//   - debt values and leverage round up
+
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+/// Rounding used for collateral values and anything derived from them.
+pub const COLLATERAL: Rounding = Rounding::Down;
+
+/// Rounding used for debt values and anything derived from them.
+pub const DEBT: Rounding = Rounding::Up;
+
+/// Computes `a * b / denominator` with a 128-bit intermediate.
+///
+/// Returns `None` when `denominator` is zero or the result does not fit in u64.
+pub fn mul_div(a: u64, b: u64, denominator: u64, rounding: Rounding) -> Option<u64> {
+    if denominator == 0 {
+        return None;
+    }
+
+    let numerator = (a as u128) * (b as u128);
+    let denominator = denominator as u128;
+    let mut result = numerator / denominator;
+    if rounding == Rounding::Up && result * denominator != numerator {
+        result += 1;
+    }
+
+    u64::try_from(result).ok()
+}
+
+/// Computes `a / denominator` in the given direction.
+pub fn div(a: u64, denominator: u64, rounding: Rounding) -> Option<u64> {
+    mul_div(a, 1, denominator, rounding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_division_ignores_direction() {
+        assert_eq!(mul_div(10, 30, 100, Rounding::Down), Some(3));
+        assert_eq!(mul_div(10, 30, 100, Rounding::Up), Some(3));
+    }
+
+    #[test]
+    fn remainder_rounds_in_requested_direction() {
+        assert_eq!(div(7, 2, Rounding::Down), Some(3));
+        assert_eq!(div(7, 2, Rounding::Up), Some(4));
+        assert_eq!(mul_div(1, 1, 3, Rounding::Down), Some(0));
+        assert_eq!(mul_div(1, 1, 3, Rounding::Up), Some(1));
+    }
+
+    #[test]
+    fn zero_denominator_is_none() {
+        assert_eq!(div(1, 0, Rounding::Down), None);
+        assert_eq!(div(0, 0, Rounding::Up), None);
+    }
+
+    #[test]
+    fn intermediate_product_does_not_overflow() {
+        assert_eq!(
+            mul_div(u64::MAX, 1000, 1000, Rounding::Down),
+            Some(u64::MAX)
+        );
+        assert_eq!(mul_div(u64::MAX, 2, 1, Rounding::Down), None);
+    }
+}
@@ -0,0 +1,352 @@
+// ========== PRICE SOURCES ==========
+//
+// Every way the registry learns an asset price goes through `PriceSource`.
+// Instructions resolve a price and cache it on the asset, and the health
+// math reads the cached registry price. Adding an oracle means adding a
+// source here and resolving through it in `resolve_with_fallback`.
+//
+// The fallback chain tries an asset's primary oracle, then its fallback
+// oracle, then the cached price if it is recent enough. A source fails when
+// its accounts are missing or wrong, its answer is unusable, or its answer
+// is older than the asset's price age window. `refresh_price` walks the
+// chain for one asset; borrows, withdrawals and liquidations walk it for
+// every oracle-priced asset they value, so their health check reads the
+// feeds live.
+
+use std::convert::TryFrom;
+
+use anchor_lang::prelude::*;
+
+use crate::{chainlink, AssetInfo, AssetRegistry, ErrorCode};
+
+/// Chainlink store program that owns the data feeds
+pub const CHAINLINK_PROGRAM_ID: Pubkey = pubkey!("HEvSKofvBgfaexv23kMabbYqxasxU3mQ4ibBMEmJWHny");
+
+/// Where an asset's price comes from
+#[derive(Debug, Clone, Copy, AnchorSerialize, AnchorDeserialize, PartialEq, Eq, InitSpace)]
+pub enum OracleSource {
+    /// Posted by the registry authority
+    Authority,
+    /// Read from a Chainlink data feed, rescaled to `price_decimals`
+    Chainlink { feed: Pubkey, price_decimals: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PriceResult {
+    /// Price in the registry's units
+    pub price: u64,
+    /// Slot the price was observed at
+    pub slot: u64,
+}
+
+pub trait PriceSource {
+    /// Resolves a price for `asset` from the source's `accounts`.
+    fn resolve_price(
+        &self,
+        asset: &AssetInfo,
+        accounts: &[AccountInfo],
+        clock: &Clock,
+        config: &AssetRegistry,
+    ) -> Result<PriceResult>;
+}
+
+/// Price posted directly by the registry authority
+pub struct AuthorityPrice {
+    pub price: u64,
+}
+
+impl PriceSource for AuthorityPrice {
+    fn resolve_price(
+        &self,
+        _asset: &AssetInfo,
+        _accounts: &[AccountInfo],
+        clock: &Clock,
+        _config: &AssetRegistry,
+    ) -> Result<PriceResult> {
+        if self.price == 0 {
+            return Err(ErrorCode::InvalidPrice.into());
+        }
+        Ok(PriceResult {
+            price: self.price,
+            slot: clock.slot,
+        })
+    }
+}
+
+/// Latest answer of a Chainlink feed. Expects the feed and the Chainlink
+/// program as `accounts`.
+pub struct ChainlinkFeed {
+    pub feed: Pubkey,
+    pub price_decimals: u8,
+}
+
+impl ChainlinkFeed {
+    pub fn from_source(source: &OracleSource) -> Option<Self> {
+        match *source {
+            OracleSource::Chainlink {
+                feed,
+                price_decimals,
+            } => Some(ChainlinkFeed {
+                feed,
+                price_decimals,
+            }),
+            OracleSource::Authority => None,
+        }
+    }
+}
+
+impl PriceSource for ChainlinkFeed {
+    fn resolve_price(
+        &self,
+        _asset: &AssetInfo,
+        accounts: &[AccountInfo],
+        _clock: &Clock,
+        _config: &AssetRegistry,
+    ) -> Result<PriceResult> {
+        let (feed_account, program) = match accounts {
+            [feed_account, program, ..] => (feed_account, program),
+            _ => return Err(ErrorCode::InvalidOracleAccount.into()),
+        };
+        if feed_account.key() != self.feed || program.key() != CHAINLINK_PROGRAM_ID {
+            return Err(ErrorCode::InvalidOracleAccount.into());
+        }
+
+        let round = chainlink::latest_round_data(program.clone(), feed_account.clone())?;
+        let feed_decimals = chainlink::decimals(program.clone(), feed_account.clone())?;
+        let price = rescale(round.answer, feed_decimals, self.price_decimals)
+            .filter(|price| *price > 0)
+            .ok_or(ErrorCode::InvalidPrice)?;
+
+        Ok(PriceResult {
+            price,
+            slot: round.slot,
+        })
+    }
+}
+
+/// Price already cached on the asset, usable while it is younger than the
+/// asset's price age window
+pub struct CachedPrice;
+
+impl PriceSource for CachedPrice {
+    fn resolve_price(
+        &self,
+        asset: &AssetInfo,
+        _accounts: &[AccountInfo],
+        clock: &Clock,
+        _config: &AssetRegistry,
+    ) -> Result<PriceResult> {
+        // A zero window disables the cached fallback
+        if asset.max_price_age_slots == 0
+            || asset.price == 0
+            || is_stale(asset.price_slot, clock.slot, asset.max_price_age_slots)
+        {
+            return Err(ErrorCode::MissingOraclePrice.into());
+        }
+        Ok(PriceResult {
+            price: asset.price,
+            slot: asset.price_slot,
+        })
+    }
+}
+
+/// Where the fallback chain found its price
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriceOrigin {
+    Primary,
+    Fallback,
+    Cached,
+}
+
+/// Whether any source in `asset`'s fallback chain is an oracle feed, as
+/// opposed to prices posted by the authority
+pub fn has_oracle_feed(asset: &AssetInfo) -> bool {
+    [Some(asset.oracle), asset.fallback_oracle]
+        .iter()
+        .flatten()
+        .any(|source| ChainlinkFeed::from_source(source).is_some())
+}
+
+/// Resolves `asset`'s price through its fallback chain. `accounts` holds the
+/// feed of each Chainlink source in the chain and the Chainlink program, in
+/// any order and possibly mixed with unrelated accounts. Authority sources
+/// take no accounts and are skipped, since their price only arrives through
+/// `update_asset_price`.
+pub fn resolve_with_fallback(
+    asset: &AssetInfo,
+    accounts: &[AccountInfo],
+    clock: &Clock,
+    config: &AssetRegistry,
+) -> Result<(PriceResult, PriceOrigin)> {
+    let chain = [
+        (Some(asset.oracle), PriceOrigin::Primary),
+        (asset.fallback_oracle, PriceOrigin::Fallback),
+    ];
+
+    for (source, origin) in chain.iter() {
+        let feed = match source.as_ref().and_then(ChainlinkFeed::from_source) {
+            Some(feed) => feed,
+            None => continue,
+        };
+        let feed_accounts = [feed.feed, CHAINLINK_PROGRAM_ID]
+            .iter()
+            .filter_map(|key| accounts.iter().find(|info| info.key == key).cloned())
+            .collect::<Vec<_>>();
+        let resolved = feed
+            .resolve_price(asset, &feed_accounts, clock, config)
+            .and_then(|resolved| {
+                if is_stale(resolved.slot, clock.slot, asset.max_price_age_slots) {
+                    Err(ErrorCode::StaleOraclePrice.into())
+                } else {
+                    Ok(resolved)
+                }
+            });
+
+        match resolved {
+            Ok(resolved) => return Ok((resolved, *origin)),
+            Err(err) => msg!("Asset {} {:?} oracle failed: {}", asset.id, origin, err),
+        }
+    }
+
+    let resolved = CachedPrice.resolve_price(asset, &[], clock, config)?;
+    Ok((resolved, PriceOrigin::Cached))
+}
+
+/// Whether a price observed at `observed_slot` is older than `max_age_slots`
+/// at `slot`. A zero window never goes stale.
+pub fn is_stale(observed_slot: u64, slot: u64, max_age_slots: u64) -> bool {
+    max_age_slots != 0 && slot.saturating_sub(observed_slot) > max_age_slots
+}
+
+/// Converts a feed answer with `from_decimals` into a registry price with
+/// `to_decimals`, rounding down. Negative or oversized answers are `None`.
+pub fn rescale(answer: i128, from_decimals: u8, to_decimals: u8) -> Option<u64> {
+    if answer < 0 {
+        return None;
+    }
+    let scaled = if to_decimals >= from_decimals {
+        answer.checked_mul(10i128.checked_pow((to_decimals - from_decimals) as u32)?)?
+    } else {
+        answer / 10i128.checked_pow((from_decimals - to_decimals) as u32)?
+    };
+    u64::try_from(scaled).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn asset(price_slot: u64, max_price_age_slots: u64) -> AssetInfo {
+        AssetInfo {
+            price_slot,
+            max_price_age_slots,
+            ..fixtures::asset(0, 100)
+        }
+    }
+
+    fn registry() -> AssetRegistry {
+        fixtures::registry(Vec::new())
+    }
+
+    fn at_slot(slot: u64) -> Clock {
+        Clock {
+            slot,
+            ..Clock::default()
+        }
+    }
+
+    #[test]
+    fn rescale_moves_the_decimal_point() {
+        // $151.23456789 from an 8-decimal feed
+        assert_eq!(rescale(15_123_456_789, 8, 0), Some(151));
+        assert_eq!(rescale(15_123_456_789, 8, 2), Some(15_123));
+        assert_eq!(rescale(15_123_456_789, 8, 10), Some(1_512_345_678_900));
+        assert_eq!(rescale(-1, 8, 8), None);
+        assert_eq!(rescale(i128::MAX, 8, 8), None);
+    }
+
+    #[test]
+    fn prices_go_stale_after_the_window() {
+        assert!(!is_stale(100, 150, 50));
+        assert!(is_stale(100, 151, 50));
+        assert!(!is_stale(100, 1_000_000, 0));
+    }
+
+    #[test]
+    fn chain_falls_back_to_a_recent_cached_price_only() {
+        let config = registry();
+
+        let (resolved, origin) =
+            resolve_with_fallback(&asset(100, 50), &[], &at_slot(150), &config).unwrap();
+        assert_eq!(origin, PriceOrigin::Cached);
+        assert_eq!(
+            resolved,
+            PriceResult {
+                price: 100,
+                slot: 100
+            }
+        );
+
+        let missing = anchor_lang::error::Error::from(ErrorCode::MissingOraclePrice);
+        let stale = resolve_with_fallback(&asset(100, 50), &[], &at_slot(151), &config);
+        assert_eq!(stale.unwrap_err(), missing);
+        let disabled = resolve_with_fallback(&asset(100, 0), &[], &at_slot(100), &config);
+        assert_eq!(disabled.unwrap_err(), missing);
+    }
+
+    #[test]
+    fn chainlink_source_without_accounts_fails_over() {
+        let mut asset = asset(100, 50);
+        asset.oracle = OracleSource::Chainlink {
+            feed: Pubkey::new_unique(),
+            price_decimals: 0,
+        };
+        asset.fallback_oracle = Some(asset.oracle);
+
+        let (_, origin) = resolve_with_fallback(&asset, &[], &at_slot(120), &registry()).unwrap();
+        assert_eq!(origin, PriceOrigin::Cached);
+    }
+
+    #[test]
+    fn feeds_are_read_only_with_the_chainlink_program() {
+        let feed = Pubkey::new_unique();
+        let mut asset = asset(100, 50);
+        assert!(!has_oracle_feed(&asset));
+        asset.fallback_oracle = Some(OracleSource::Chainlink {
+            feed,
+            price_decimals: 0,
+        });
+        assert!(has_oracle_feed(&asset));
+
+        // The feed among unrelated accounts, but no Chainlink program
+        let (co_signer, owner) = (Pubkey::new_unique(), Pubkey::default());
+        let (mut feed_lamports, mut signer_lamports) = (0, 0);
+        let (mut feed_data, mut signer_data) = (Vec::new(), Vec::new());
+        let accounts = [
+            AccountInfo::new(
+                &co_signer,
+                true,
+                false,
+                &mut signer_lamports,
+                &mut signer_data,
+                &owner,
+                false,
+                0,
+            ),
+            AccountInfo::new(
+                &feed,
+                false,
+                false,
+                &mut feed_lamports,
+                &mut feed_data,
+                &owner,
+                false,
+                0,
+            ),
+        ];
+        let (_, origin) =
+            resolve_with_fallback(&asset, &accounts, &at_slot(120), &registry()).unwrap();
+        assert_eq!(origin, PriceOrigin::Cached);
+    }
+}
@@ -0,0 +1,704 @@
+// ========== TEST SCENARIOS ==========
+//
+// Builder for behavioral tests. Assets and users are named, steps run in
+// order through the same position-change functions the instructions use
+// (guard, sizes, caps, interest, health) and assertions can sit between any
+// two steps:
+//
+//   Scenario::new()
+//       .asset("SOL", 150)
+//       .asset("USDC", 1)
+//       .risk("SOL", "USDC", 100)
+//       .deposit("alice", "SOL", 10)
+//       .borrow("alice", "USDC", 1_000)
+//       .price("SOL", 100)
+//       .expect_health("alice", 1_000);
+//
+// Assets have 6 decimals and full LTV, users open cross-margin obligations.
+// The clock starts at slot 0 and only moves with `advance_slots`. Token
+// movement, co-signers, stats and sequence numbers belong to the instruction
+// wrappers and are not modeled.
+// A failing step panics with its step number and description.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program_error::ProgramError;
+
+use crate::fixtures;
+use crate::guard::Operation;
+use crate::interest::{RateModel, INDEX_ONE, SLOTS_PER_YEAR};
+use crate::oracle::PriceResult;
+use crate::{
+    apply_borrow, apply_deposit, apply_liquidation, apply_repay, apply_withdraw, compute_health,
+    store_price, AssetRegistry, ErrorCode, MarginMode, Obligation,
+};
+
+pub struct Scenario {
+    registry: AssetRegistry,
+    /// Asset names, indexed by asset id
+    assets: Vec<&'static str>,
+    users: Vec<(&'static str, Obligation)>,
+    step: usize,
+    slot: u64,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scenario {
+    pub fn new() -> Self {
+        Scenario {
+            registry: fixtures::registry(Vec::new()),
+            assets: Vec::new(),
+            users: Vec::new(),
+            step: 0,
+            slot: 0,
+        }
+    }
+
+    // ---------- Market setup ----------
+
+    pub fn asset(mut self, name: &'static str, price: u64) -> Self {
+        let id = self.assets.len() as u8;
+        self.registry.assets.push(fixtures::asset(id, price));
+        self.assets.push(name);
+        self
+    }
+
+    /// Gives the asset a mint, so the program custodies it in a vault
+    pub fn custodied(mut self, asset: &str) -> Self {
+        let id = self.asset_id(asset);
+        self.registry.assets[id as usize].mint = Pubkey::new_unique();
+        self
+    }
+
+    pub fn ltv(mut self, asset: &str, ltv_bps: u16) -> Self {
+        let id = self.asset_id(asset);
+        self.registry.assets[id as usize].ltv_bps = ltv_bps;
+        self
+    }
+
+    pub fn risk(mut self, asset_a: &str, asset_b: &str, risk_level: u8) -> Self {
+        let (asset_id_a, asset_id_b) = (self.asset_id(asset_a), self.asset_id(asset_b));
+        self.registry
+            .risk_params
+            .push(fixtures::risk_param(asset_id_a, asset_id_b, risk_level));
+        self
+    }
+
+    pub fn open_interest_cap(mut self, asset_a: &str, asset_b: &str, cap: u64) -> Self {
+        let (asset_id_a, asset_id_b) = (self.asset_id(asset_a), self.asset_id(asset_b));
+        let param = self
+            .registry
+            .risk_params
+            .iter_mut()
+            .find(|p| p.asset_id_a == asset_id_a && p.asset_id_b == asset_id_b)
+            .unwrap_or_else(|| panic!("no risk param for {}-{}", asset_a, asset_b));
+        param.max_open_interest = cap;
+        self
+    }
+
+    pub fn rate_model(mut self, asset: &str, model: RateModel) -> Self {
+        let id = self.asset_id(asset);
+        self.registry.assets[id as usize].rate_model = model;
+        self
+    }
+
+    pub fn price(mut self, asset: &str, price: u64) -> Self {
+        let id = self.asset_id(asset);
+        self.registry.assets[id as usize].price = price;
+        self
+    }
+
+    pub fn price_bounds(mut self, asset: &str, min_price: u64, max_price: u64) -> Self {
+        let id = self.asset_id(asset);
+        self.registry.assets[id as usize].min_price = min_price;
+        self.registry.assets[id as usize].max_price = max_price;
+        self
+    }
+
+    pub fn max_price_age(mut self, asset: &str, slots: u64) -> Self {
+        let id = self.asset_id(asset);
+        self.registry.assets[id as usize].max_price_age_slots = slots;
+        self
+    }
+
+    pub fn paused(mut self, paused: bool) -> Self {
+        self.registry.paused = paused;
+        self
+    }
+
+    pub fn position_minimums(mut self, min_deposit_value: u64, min_borrow_value: u64) -> Self {
+        self.registry.min_deposit_value = min_deposit_value;
+        self.registry.min_borrow_value = min_borrow_value;
+        self
+    }
+
+    pub fn liquidation_cooldown(mut self, cooldown_slots: u64) -> Self {
+        self.registry.liquidation_cooldown_slots = cooldown_slots;
+        self
+    }
+
+    pub fn advance_slots(mut self, slots: u64) -> Self {
+        self.slot += slots;
+        self
+    }
+
+    // ---------- User steps ----------
+
+    /// Posts a price the way the price update instructions do, through the
+    /// asset's bounds.
+    pub fn post_price(mut self, asset: &str, price: u64) -> Self {
+        self.step += 1;
+        let id = self.asset_id(asset);
+        let resolved = PriceResult {
+            price,
+            slot: self.slot,
+        };
+        if let Err(e) = store_price(&mut self.registry, id, resolved) {
+            panic!(
+                "step {} (post {} price {}) failed: {:?}",
+                self.step, asset, price, e
+            );
+        }
+        self
+    }
+
+    pub fn deposit(self, user: &'static str, asset: &str, amount: u64) -> Self {
+        self.expect_ok(user, Operation::Deposit, asset, amount)
+    }
+
+    pub fn withdraw(self, user: &'static str, asset: &str, amount: u64) -> Self {
+        self.expect_ok(user, Operation::Withdraw, asset, amount)
+    }
+
+    pub fn borrow(self, user: &'static str, asset: &str, amount: u64) -> Self {
+        self.expect_ok(user, Operation::Borrow, asset, amount)
+    }
+
+    pub fn repay(self, user: &'static str, asset: &str, amount: u64) -> Self {
+        self.expect_ok(user, Operation::Repay, asset, amount)
+    }
+
+    /// Runs a step that must fail with `expected`; state is left unchanged.
+    pub fn expect_error(
+        mut self,
+        user: &'static str,
+        operation: Operation,
+        asset: &str,
+        amount: u64,
+        expected: ErrorCode,
+    ) -> Self {
+        let step = self.next_step(user, operation, asset, amount);
+        let actual = self.apply(user, operation, asset, amount);
+        assert_eq!(
+            actual.map_err(ProgramError::from),
+            Err(ProgramError::from(Error::from(expected))),
+            "{}",
+            step
+        );
+        self
+    }
+
+    /// Liquidates `user`, repaying `amount` of `repay_asset` and seizing
+    /// `collateral_asset`.
+    pub fn liquidate(
+        mut self,
+        user: &'static str,
+        repay_asset: &str,
+        amount: u64,
+        collateral_asset: &str,
+    ) -> Self {
+        self.step += 1;
+        if let Err(e) = self.apply_liquidation(user, repay_asset, amount, collateral_asset) {
+            panic!("step {} (liquidate {}) failed: {:?}", self.step, user, e);
+        }
+        self
+    }
+
+    /// Runs a liquidation that must fail with `expected`; state is left
+    /// unchanged.
+    pub fn expect_liquidation_error(
+        mut self,
+        user: &'static str,
+        repay_asset: &str,
+        amount: u64,
+        collateral_asset: &str,
+        expected: ErrorCode,
+    ) -> Self {
+        self.step += 1;
+        let actual = self.apply_liquidation(user, repay_asset, amount, collateral_asset);
+        assert_eq!(
+            actual.map_err(ProgramError::from),
+            Err(ProgramError::from(Error::from(expected))),
+            "step {} (liquidate {})",
+            self.step,
+            user
+        );
+        self
+    }
+
+    // ---------- Assertions ----------
+
+    /// Asserts the user's health score (x1000); `u64::MAX` means no borrows.
+    pub fn expect_health(self, user: &'static str, health_x1000: u64) -> Self {
+        let actual = compute_health(self.obligation(user), &self.registry)
+            .unwrap_or_else(|e| panic!("after step {}: health of {}: {:?}", self.step, user, e));
+        assert_eq!(
+            actual, health_x1000,
+            "after step {}: health of {}",
+            self.step, user
+        );
+        self
+    }
+
+    /// Asserts the user's deposit of `asset`; 0 means no position.
+    pub fn expect_deposit(self, user: &'static str, asset: &str, amount: u64) -> Self {
+        let asset_id = self.asset_id(asset);
+        let actual = self
+            .obligation(user)
+            .deposits
+            .iter()
+            .find(|p| p.asset_id == asset_id)
+            .map_or(0, |p| p.amount);
+        assert_eq!(
+            actual, amount,
+            "after step {}: {} deposit of {}",
+            self.step, asset, user
+        );
+        self
+    }
+
+    pub fn expect_price(self, asset: &str, price: u64) -> Self {
+        let actual = self.registry.assets[self.asset_id(asset) as usize].price;
+        assert_eq!(actual, price, "after step {}: {} price", self.step, asset);
+        self
+    }
+
+    pub fn expect_paused(self, paused: bool) -> Self {
+        assert_eq!(
+            self.registry.paused, paused,
+            "after step {}: market paused",
+            self.step
+        );
+        self
+    }
+
+    /// Asserts the asset's borrow index, accrued to the current slot.
+    pub fn expect_borrow_index(self, asset: &str, index: u128) -> Self {
+        let asset_info = &self.registry.assets[self.asset_id(asset) as usize];
+        let mut reserve = asset_info.reserve;
+        reserve
+            .accrue(&asset_info.rate_model, self.slot)
+            .unwrap_or_else(|e| panic!("after step {}: accrue {}: {:?}", self.step, asset, e));
+        assert_eq!(
+            reserve.borrow_index, index,
+            "after step {}: {} borrow index",
+            self.step, asset
+        );
+        self
+    }
+
+    // ---------- Internals ----------
+
+    fn expect_ok(
+        mut self,
+        user: &'static str,
+        operation: Operation,
+        asset: &str,
+        amount: u64,
+    ) -> Self {
+        let step = self.next_step(user, operation, asset, amount);
+        if let Err(e) = self.apply(user, operation, asset, amount) {
+            panic!("{} failed: {:?}", step, e);
+        }
+        self
+    }
+
+    fn next_step(&mut self, user: &str, operation: Operation, asset: &str, amount: u64) -> String {
+        self.step += 1;
+        format!(
+            "step {} ({} {:?} {} {})",
+            self.step, user, operation, amount, asset
+        )
+    }
+
+    /// Applies the step to copies of the obligation and registry and keeps
+    /// them only if it succeeds, like a transaction.
+    fn apply(
+        &mut self,
+        user: &'static str,
+        operation: Operation,
+        asset: &str,
+        amount: u64,
+    ) -> Result<()> {
+        let asset_id = self.asset_id(asset);
+        let slot = self.slot;
+        let mut obligation = self.obligation_mut(user).clone();
+        let mut registry = self.registry.clone();
+        let (o, r) = (&mut obligation, &mut registry);
+        match operation {
+            Operation::Deposit => apply_deposit(o, r, asset_id, amount, slot)?,
+            Operation::Withdraw => apply_withdraw(o, r, asset_id, amount, slot).map(|_| ())?,
+            Operation::Borrow => apply_borrow(o, r, asset_id, amount, slot).map(|_| ())?,
+            Operation::Repay => apply_repay(o, r, asset_id, amount, slot)?,
+        }
+
+        *self.obligation_mut(user) = obligation;
+        self.registry = registry;
+        Ok(())
+    }
+
+    fn apply_liquidation(
+        &mut self,
+        user: &'static str,
+        repay_asset: &str,
+        amount: u64,
+        collateral_asset: &str,
+    ) -> Result<()> {
+        let (repay_id, collateral_id) =
+            (self.asset_id(repay_asset), self.asset_id(collateral_asset));
+
+        let mut obligation = self.obligation_mut(user).clone();
+        let mut registry = self.registry.clone();
+        apply_liquidation(
+            &mut obligation,
+            &mut registry,
+            repay_id,
+            amount,
+            collateral_id,
+            self.slot,
+        )?;
+
+        *self.obligation_mut(user) = obligation;
+        self.registry = registry;
+        Ok(())
+    }
+
+    fn asset_id(&self, name: &str) -> u8 {
+        self.assets
+            .iter()
+            .position(|a| *a == name)
+            .unwrap_or_else(|| panic!("unknown asset {}", name)) as u8
+    }
+
+    fn obligation(&self, user: &str) -> &Obligation {
+        self.users
+            .iter()
+            .find(|(name, _)| *name == user)
+            .map(|(_, obligation)| obligation)
+            .unwrap_or_else(|| panic!("{} has no obligation", user))
+    }
+
+    /// The user's obligation, opened on first use
+    fn obligation_mut(&mut self, user: &'static str) -> &mut Obligation {
+        if !self.users.iter().any(|(name, _)| *name == user) {
+            self.users
+                .push((user, fixtures::obligation(MarginMode::Cross)));
+        }
+        self.users
+            .iter_mut()
+            .find(|(name, _)| *name == user)
+            .map(|(_, obligation)| obligation)
+            .unwrap()
+    }
+}
+
+#[test]
+fn price_drop_erodes_health() {
+    Scenario::new()
+        .asset("SOL", 150)
+        .asset("USDC", 1)
+        .risk("SOL", "USDC", 100)
+        .deposit("alice", "SOL", 10)
+        .expect_health("alice", u64::MAX)
+        .borrow("alice", "USDC", 1_000)
+        .expect_health("alice", 1_500)
+        .price("SOL", 100)
+        .expect_health("alice", 1_000)
+        .expect_error("alice", Operation::Borrow, "USDC", 1, ErrorCode::Unhealthy)
+        .repay("alice", "USDC", 500)
+        .expect_health("alice", 2_000);
+}
+
+#[test]
+fn unhealthy_obligations_can_reduce_risk() {
+    Scenario::new()
+        .asset("SOL", 150)
+        .asset("USDC", 1)
+        .risk("SOL", "USDC", 100)
+        .deposit("alice", "SOL", 10)
+        .borrow("alice", "USDC", 1_000)
+        .price("SOL", 50)
+        .expect_health("alice", 500)
+        // Neither step restores health on its own
+        .repay("alice", "USDC", 200)
+        .expect_health("alice", 625)
+        .deposit("alice", "SOL", 2)
+        .expect_health("alice", 750)
+        .expect_error("alice", Operation::Withdraw, "SOL", 1, ErrorCode::Unhealthy)
+        .expect_error("alice", Operation::Borrow, "USDC", 1, ErrorCode::Unhealthy);
+}
+
+#[test]
+fn users_are_independent() {
+    Scenario::new()
+        .asset("SOL", 150)
+        .asset("USDC", 1)
+        .ltv("SOL", 5_000)
+        .deposit("alice", "SOL", 10)
+        .deposit("bob", "USDC", 1_000)
+        .borrow("alice", "USDC", 375)
+        .expect_health("alice", 1_000)
+        .expect_health("bob", u64::MAX)
+        .expect_error(
+            "bob",
+            Operation::Withdraw,
+            "USDC",
+            1_001,
+            ErrorCode::InsufficientDeposit,
+        )
+        .withdraw("bob", "USDC", 500)
+        .paused(true)
+        .expect_error(
+            "bob",
+            Operation::Withdraw,
+            "USDC",
+            1,
+            ErrorCode::MarketPaused,
+        )
+        .repay("alice", "USDC", 375)
+        .expect_health("alice", u64::MAX);
+}
+
+#[test]
+fn steps_enforce_instruction_rules() {
+    Scenario::new()
+        .asset("SOL", 150)
+        .asset("USDC", 1)
+        .risk("SOL", "USDC", 100)
+        .position_minimums(1_000, 100)
+        .expect_error(
+            "alice",
+            Operation::Deposit,
+            "SOL",
+            6,
+            ErrorCode::DepositBelowMinimum,
+        )
+        .deposit("alice", "SOL", 10)
+        .expect_error(
+            "alice",
+            Operation::Borrow,
+            "USDC",
+            99,
+            ErrorCode::BorrowBelowMinimum,
+        )
+        .borrow("alice", "USDC", 150)
+        .expect_error(
+            "alice",
+            Operation::Repay,
+            "USDC",
+            100,
+            ErrorCode::BorrowBelowMinimum,
+        )
+        .expect_error(
+            "alice",
+            Operation::Repay,
+            "USDC",
+            0,
+            ErrorCode::AmountTooSmall,
+        )
+        .repay("alice", "USDC", 150)
+        .expect_error(
+            "alice",
+            Operation::Withdraw,
+            "SOL",
+            0,
+            ErrorCode::AmountTooSmall,
+        );
+}
+
+#[test]
+fn cross_obligations_cannot_borrow_capped_assets() {
+    Scenario::new()
+        .asset("SOL", 150)
+        .asset("USDC", 1)
+        .asset("BONK", 1)
+        .risk("SOL", "USDC", 100)
+        .risk("SOL", "BONK", 50)
+        .open_interest_cap("SOL", "BONK", 1_000_000)
+        .deposit("bob", "USDC", 1_000)
+        .deposit("bob", "BONK", 1_000)
+        .deposit("alice", "SOL", 10)
+        .borrow("alice", "USDC", 100)
+        .expect_error(
+            "alice",
+            Operation::Borrow,
+            "BONK",
+            100,
+            ErrorCode::CappedAssetRequiresIsolated,
+        );
+}
+
+#[test]
+fn bookkeeping_collateral_cannot_drain_a_vault() {
+    Scenario::new()
+        .asset("FAKE", 1_000)
+        .asset("USDC", 1)
+        .custodied("USDC")
+        .risk("FAKE", "USDC", 100)
+        // FAKE has no mint, so depositing it moves no tokens
+        .deposit("alice", "FAKE", 1_000_000)
+        .expect_error(
+            "alice",
+            Operation::Borrow,
+            "USDC",
+            1_000,
+            ErrorCode::MixedCustody,
+        )
+        .deposit("bob", "USDC", 1_000)
+        .expect_error("bob", Operation::Borrow, "FAKE", 1, ErrorCode::MixedCustody)
+        .expect_error(
+            "bob",
+            Operation::Deposit,
+            "FAKE",
+            1,
+            ErrorCode::MixedCustody,
+        );
+}
+
+#[test]
+fn deposits_only_change_utilization_from_their_slot_on() {
+    // Borrow rate equals utilization
+    let model = RateModel {
+        base_rate_bps: 0,
+        slope1_bps: 10_000,
+        slope2_bps: 0,
+        optimal_utilization_bps: 10_000,
+    };
+    Scenario::new()
+        .asset("SOL", 150)
+        .asset("USDC", 1)
+        .risk("SOL", "USDC", 100)
+        .rate_model("USDC", model)
+        .deposit("bob", "USDC", 1_000_000)
+        .deposit("alice", "SOL", 10_000)
+        .borrow("alice", "USDC", 800_000)
+        // A year at 80% utilization
+        .advance_slots(SLOTS_PER_YEAR)
+        .expect_borrow_index("USDC", INDEX_ONE * 18 / 10)
+        // 1_440_000 owed against 2_000_000 deposited: 72% from here on
+        .deposit("carol", "USDC", 1_000_000)
+        .advance_slots(SLOTS_PER_YEAR)
+        .expect_borrow_index("USDC", INDEX_ONE * 18 / 10 * 172 / 100);
+}
+
+#[test]
+fn out_of_bounds_price_trips_the_circuit_breaker() {
+    Scenario::new()
+        .asset("SOL", 150)
+        .price_bounds("SOL", 10, 1_000)
+        .post_price("SOL", 1_000)
+        .expect_price("SOL", 1_000)
+        .expect_paused(false)
+        // An 8-decimal feed read as whole dollars
+        .post_price("SOL", 15_000_000_000)
+        .expect_price("SOL", 1_000)
+        .expect_paused(true)
+        .post_price("SOL", 9)
+        .expect_price("SOL", 1_000)
+        .paused(false)
+        .post_price("SOL", 10)
+        .expect_price("SOL", 10)
+        .expect_paused(false);
+}
+
+#[test]
+fn stale_prices_block_risk_increasing_steps() {
+    Scenario::new()
+        .asset("SOL", 150)
+        .asset("USDC", 1)
+        .risk("SOL", "USDC", 100)
+        .max_price_age("SOL", 100)
+        .deposit("alice", "SOL", 10)
+        .borrow("alice", "USDC", 500)
+        .advance_slots(101)
+        .expect_error(
+            "alice",
+            Operation::Borrow,
+            "USDC",
+            1,
+            ErrorCode::StaleOraclePrice,
+        )
+        .expect_error(
+            "alice",
+            Operation::Withdraw,
+            "SOL",
+            1,
+            ErrorCode::StaleOraclePrice,
+        )
+        .price("SOL", 40)
+        .expect_liquidation_error("alice", "USDC", 100, "SOL", ErrorCode::StaleOraclePrice)
+        // Reducing risk never needs a fresh price
+        .deposit("alice", "SOL", 1)
+        .repay("alice", "USDC", 100)
+        .post_price("SOL", 150)
+        .borrow("alice", "USDC", 100);
+}
+
+#[test]
+fn liquidation_seizes_collateral_with_bonus() {
+    Scenario::new()
+        .asset("SOL", 150)
+        .asset("USDC", 1)
+        .risk("SOL", "USDC", 80)
+        .deposit("alice", "SOL", 10_000)
+        .borrow("alice", "USDC", 1_000_000)
+        .expect_liquidation_error("alice", "USDC", 1, "SOL", ErrorCode::ObligationHealthy)
+        .price("SOL", 120)
+        .expect_health("alice", 960)
+        .expect_liquidation_error(
+            "alice",
+            "USDC",
+            500_001,
+            "SOL",
+            ErrorCode::CloseFactorExceeded,
+        )
+        // 500_000 USDC plus the 5% bonus, at 120 per SOL
+        .liquidate("alice", "USDC", 500_000, "SOL")
+        .expect_deposit("alice", "SOL", 5_625)
+        .expect_health("alice", 1_080);
+}
+
+#[test]
+fn liquidations_are_throttled_per_obligation() {
+    Scenario::new()
+        .asset("SOL", 150)
+        .asset("USDC", 1)
+        .risk("SOL", "USDC", 100)
+        .liquidation_cooldown(10)
+        .deposit("alice", "SOL", 10_000)
+        .borrow("alice", "USDC", 1_000_000)
+        .price("SOL", 90)
+        .liquidate("alice", "USDC", 500_000, "SOL")
+        .expect_health("alice", 750)
+        .expect_liquidation_error(
+            "alice",
+            "USDC",
+            250_000,
+            "SOL",
+            ErrorCode::LiquidationThrottled,
+        )
+        .advance_slots(9)
+        .expect_liquidation_error(
+            "alice",
+            "USDC",
+            250_000,
+            "SOL",
+            ErrorCode::LiquidationThrottled,
+        )
+        .advance_slots(1)
+        .liquidate("alice", "USDC", 250_000, "SOL");
+}
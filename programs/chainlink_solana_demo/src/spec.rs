@@ -0,0 +1,242 @@
+// ========== HEALTH SPEC ==========
+//
+// Executable specification of `compute_health`. Each case states one health
+// rule as inputs and the expected score (x1000) or error:
+//   - value = amount * price
+//   - each borrow contributes its share of total borrow value
+//   - each deposit is weighted by the pair risk level (percent) of every
+//     borrow, summed by share; risk params are symmetric
+//   - deposits count at their LTV; collateral-disabled ones carry no weight
+//   - cross mode falls back to risk level 50 for unconfigured pairs, isolated
+//     mode rejects them
+//   - scores round down, in the protocol's favor
+//
+// When the health math changes, change this table first.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program_error::ProgramError;
+
+use crate::fixtures;
+use crate::guard::Operation;
+use crate::{
+    compute_health, marginal_health_impact, AssetInfo, AssetRegistry, ErrorCode, MarginMode,
+    Obligation, Position,
+};
+
+struct Case {
+    name: &'static str,
+    mode: MarginMode,
+    /// (asset_id, amount)
+    deposits: &'static [(u8, u64)],
+    /// (asset_id, amount)
+    borrows: &'static [(u8, u64)],
+    /// (asset_id_a, asset_id_b, risk_level)
+    risk_params: &'static [(u8, u8, u8)],
+    expected: std::result::Result<u64, ErrorCode>,
+}
+
+/// Asset prices by id; asset 3 is priced at 100 to exercise value = amount * price
+const PRICES: [u64; 6] = [1, 1, 1, 100, 1, 1];
+
+/// Asset that is not accepted as collateral
+const COLLATERAL_DISABLED: u8 = 4;
+
+/// Asset whose deposits count at 50% LTV
+const HALF_LTV: u8 = 5;
+
+const CASES: &[Case] = &[
+    Case {
+        name: "no borrows is infinitely healthy",
+        mode: MarginMode::Cross,
+        deposits: &[(0, 1_000)],
+        borrows: &[],
+        risk_params: &[],
+        expected: Ok(u64::MAX),
+    },
+    Case {
+        name: "single pair is weighted by its risk level",
+        mode: MarginMode::Cross,
+        deposits: &[(0, 1_000)],
+        borrows: &[(1, 800)],
+        risk_params: &[(0, 1, 80)],
+        expected: Ok(1_000),
+    },
+    Case {
+        name: "risk params apply in both directions",
+        mode: MarginMode::Cross,
+        deposits: &[(0, 1_000)],
+        borrows: &[(1, 800)],
+        risk_params: &[(1, 0, 80)],
+        expected: Ok(1_000),
+    },
+    Case {
+        name: "value is amount times price",
+        mode: MarginMode::Cross,
+        deposits: &[(3, 20)],
+        borrows: &[(1, 1_000)],
+        risk_params: &[(3, 1, 100)],
+        expected: Ok(2_000),
+    },
+    Case {
+        name: "cross mode defaults unconfigured pairs to 50",
+        mode: MarginMode::Cross,
+        deposits: &[(0, 1_000)],
+        borrows: &[(1, 500)],
+        risk_params: &[],
+        expected: Ok(1_000),
+    },
+    Case {
+        name: "isolated mode requires a configured pair",
+        mode: MarginMode::Isolated,
+        deposits: &[(0, 1_000)],
+        borrows: &[(1, 500)],
+        risk_params: &[],
+        expected: Err(ErrorCode::MissingRiskParam),
+    },
+    Case {
+        name: "isolated mode uses the configured pair",
+        mode: MarginMode::Isolated,
+        deposits: &[(0, 1_000)],
+        borrows: &[(1, 500)],
+        risk_params: &[(0, 1, 90)],
+        expected: Ok(1_800),
+    },
+    Case {
+        name: "risk is averaged by borrow share",
+        mode: MarginMode::Cross,
+        deposits: &[(0, 1_000)],
+        borrows: &[(1, 300), (2, 100)],
+        risk_params: &[(0, 1, 90), (0, 2, 10)],
+        expected: Ok(1_750),
+    },
+    Case {
+        name: "deposits contribute independently",
+        mode: MarginMode::Cross,
+        deposits: &[(0, 500), (2, 500)],
+        borrows: &[(1, 500)],
+        risk_params: &[(0, 1, 100), (2, 1, 20)],
+        expected: Ok(1_200),
+    },
+    Case {
+        name: "deposits are weighted by LTV",
+        mode: MarginMode::Cross,
+        deposits: &[(HALF_LTV, 2_000)],
+        borrows: &[(1, 500)],
+        risk_params: &[],
+        expected: Ok(1_000),
+    },
+    Case {
+        name: "collateral-disabled deposits carry no weight",
+        mode: MarginMode::Cross,
+        deposits: &[(0, 1_000), (COLLATERAL_DISABLED, 1_000)],
+        borrows: &[(1, 500)],
+        risk_params: &[],
+        expected: Ok(1_000),
+    },
+    Case {
+        name: "score rounds down",
+        mode: MarginMode::Cross,
+        deposits: &[(0, 1)],
+        borrows: &[(1, 3)],
+        risk_params: &[(0, 1, 100)],
+        expected: Ok(333),
+    },
+    Case {
+        name: "unknown deposit asset is rejected",
+        mode: MarginMode::Cross,
+        deposits: &[(9, 1_000)],
+        borrows: &[(1, 500)],
+        risk_params: &[],
+        expected: Err(ErrorCode::AssetNotFound),
+    },
+];
+
+fn registry(risk_params: &[(u8, u8, u8)]) -> AssetRegistry {
+    let assets = PRICES
+        .iter()
+        .enumerate()
+        .map(|(id, &price)| AssetInfo {
+            collateral_enabled: id as u8 != COLLATERAL_DISABLED,
+            ltv_bps: if id as u8 == HALF_LTV {
+                AssetInfo::FULL_LTV_BPS / 2
+            } else {
+                AssetInfo::FULL_LTV_BPS
+            },
+            ..fixtures::asset(id as u8, price)
+        })
+        .collect();
+    AssetRegistry {
+        risk_params: risk_params
+            .iter()
+            .map(|&(asset_id_a, asset_id_b, risk_level)| {
+                fixtures::risk_param(asset_id_a, asset_id_b, risk_level)
+            })
+            .collect(),
+        ..fixtures::registry(assets)
+    }
+}
+
+fn positions(entries: &[(u8, u64)]) -> Vec<Position> {
+    entries
+        .iter()
+        .map(|&(asset_id, amount)| Position {
+            asset_id,
+            amount,
+            borrow_index: 0,
+        })
+        .collect()
+}
+
+fn obligation(case: &Case) -> Obligation {
+    Obligation {
+        deposits: positions(case.deposits),
+        borrows: positions(case.borrows),
+        ..fixtures::obligation(case.mode)
+    }
+}
+
+#[test]
+fn health_rules() {
+    for case in CASES {
+        let actual = compute_health(&obligation(case), &registry(case.risk_params))
+            .map_err(ProgramError::from);
+        let expected = case
+            .expected
+            .map_err(|e| ProgramError::from(Error::from(e)));
+        assert_eq!(actual, expected, "{}", case.name);
+    }
+}
+
+#[test]
+fn marginal_impact_matches_applying_the_operation() {
+    let registry = registry(&[(0, 1, 80)]);
+    let obligation = Obligation {
+        deposits: positions(&[(0, 1_000)]),
+        borrows: positions(&[(1, 800)]),
+        ..obligation(&CASES[0])
+    };
+
+    // (operation, asset_id, amount, expected health after)
+    let table = [
+        (Operation::Deposit, 0, 1_000, 2_000),
+        (Operation::Withdraw, 0, 500, 500),
+        (Operation::Borrow, 1, 800, 500),
+        (Operation::Repay, 1, 400, 2_000),
+        (Operation::Repay, 1, 800, u64::MAX),
+    ];
+    for (operation, asset_id, amount, after) in table {
+        let change =
+            marginal_health_impact(&obligation, &registry, operation, asset_id, amount).unwrap();
+        assert_eq!(change.before_x1000, 1_000, "{:?}", operation);
+        assert_eq!(change.after_x1000, after, "{:?} {}", operation, amount);
+    }
+
+    let overdraw = marginal_health_impact(&obligation, &registry, Operation::Withdraw, 0, 1_001)
+        .map_err(ProgramError::from);
+    assert_eq!(
+        overdraw,
+        Err(ProgramError::from(Error::from(
+            ErrorCode::InsufficientDeposit
+        )))
+    );
+}
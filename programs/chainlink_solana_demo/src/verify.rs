@@ -0,0 +1,169 @@
+// ========== REGISTRY CHECK ==========
+//
+// Invariants `verify_registry` reports on. Instructions that write the
+// registry already enforce them one change at a time; this catches anything a
+// migration or import writes around them:
+//   - asset ids and non-default mints are unique
+//   - decimals fit a u64 amount, prices are non-zero and LTVs are at most 100%
+//   - price bounds are ordered and contain the current price
+//   - Chainlink sources name a feed
+//   - risk params reference existing assets, are unique per pair and weigh at
+//     most 100%
+
+use anchor_lang::prelude::*;
+
+use crate::oracle::OracleSource;
+use crate::{AssetInfo, AssetRegistry};
+
+/// Largest decimals for which one whole token still fits in a u64
+pub const MAX_DECIMALS: u8 = 19;
+
+/// Highest risk level the health math treats as meaningful (100%)
+pub const MAX_RISK_LEVEL: u8 = 100;
+
+#[derive(Debug, Clone, Copy, AnchorSerialize, AnchorDeserialize, PartialEq, Eq)]
+pub enum RegistryIssue {
+    DuplicateAssetId { asset_id: u8 },
+    DuplicateMint { asset_id: u8 },
+    InvalidDecimals { asset_id: u8 },
+    ZeroPrice { asset_id: u8 },
+    InvalidLtv { asset_id: u8 },
+    InvalidPriceBounds { asset_id: u8 },
+    MissingOracleFeed { asset_id: u8 },
+    UnknownRiskParamAsset { asset_id_a: u8, asset_id_b: u8 },
+    DuplicateRiskParam { asset_id_a: u8, asset_id_b: u8 },
+    RiskLevelOutOfRange { asset_id_a: u8, asset_id_b: u8 },
+}
+
+/// Returns every invariant violation in the registry, in registry order.
+pub fn registry_issues(registry: &AssetRegistry) -> Vec<RegistryIssue> {
+    let mut issues = Vec::new();
+
+    for (i, asset) in registry.assets.iter().enumerate() {
+        let earlier = &registry.assets[..i];
+        if earlier.iter().any(|a| a.id == asset.id) {
+            issues.push(RegistryIssue::DuplicateAssetId { asset_id: asset.id });
+        }
+        if asset.mint != Pubkey::default() && earlier.iter().any(|a| a.mint == asset.mint) {
+            issues.push(RegistryIssue::DuplicateMint { asset_id: asset.id });
+        }
+        if asset.decimals > MAX_DECIMALS {
+            issues.push(RegistryIssue::InvalidDecimals { asset_id: asset.id });
+        }
+        if asset.price == 0 {
+            issues.push(RegistryIssue::ZeroPrice { asset_id: asset.id });
+        }
+        if asset.ltv_bps > AssetInfo::FULL_LTV_BPS {
+            issues.push(RegistryIssue::InvalidLtv { asset_id: asset.id });
+        }
+        let bounds_inverted = asset.max_price != 0 && asset.min_price > asset.max_price;
+        if bounds_inverted || (asset.price != 0 && !asset.price_within_bounds(asset.price)) {
+            issues.push(RegistryIssue::InvalidPriceBounds { asset_id: asset.id });
+        }
+        let missing_feed = [Some(asset.oracle), asset.fallback_oracle]
+            .iter()
+            .flatten()
+            .any(|source| {
+                matches!(source, OracleSource::Chainlink { feed, .. } if *feed == Pubkey::default())
+            });
+        if missing_feed {
+            issues.push(RegistryIssue::MissingOracleFeed { asset_id: asset.id });
+        }
+    }
+
+    for (i, param) in registry.risk_params.iter().enumerate() {
+        let (asset_id_a, asset_id_b) = (param.asset_id_a, param.asset_id_b);
+        let known = |id: u8| registry.assets.iter().any(|a| a.id == id);
+        if !known(asset_id_a) || !known(asset_id_b) {
+            issues.push(RegistryIssue::UnknownRiskParamAsset {
+                asset_id_a,
+                asset_id_b,
+            });
+        }
+        if registry.risk_params[..i].iter().any(|p| {
+            (p.asset_id_a == asset_id_a && p.asset_id_b == asset_id_b)
+                || (p.asset_id_a == asset_id_b && p.asset_id_b == asset_id_a)
+        }) {
+            issues.push(RegistryIssue::DuplicateRiskParam {
+                asset_id_a,
+                asset_id_b,
+            });
+        }
+        if param.risk_level > MAX_RISK_LEVEL {
+            issues.push(RegistryIssue::RiskLevelOutOfRange {
+                asset_id_a,
+                asset_id_b,
+            });
+        }
+    }
+
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, asset, risk_param as param};
+    use crate::PairRiskParam;
+
+    fn registry(assets: Vec<AssetInfo>, risk_params: Vec<PairRiskParam>) -> AssetRegistry {
+        AssetRegistry {
+            risk_params,
+            ..fixtures::registry(assets)
+        }
+    }
+
+    #[test]
+    fn consistent_registry_has_no_issues() {
+        let registry = registry(vec![asset(0, 1), asset(1, 1)], vec![param(0, 1, 80)]);
+        assert!(registry_issues(&registry).is_empty());
+    }
+
+    #[test]
+    fn every_violation_is_reported() {
+        let mint = Pubkey::new_unique();
+        let mut assets = vec![asset(0, 1), asset(0, 1), asset(2, 1), asset(3, 1)];
+        assets[2].mint = mint;
+        assets[3].mint = mint;
+        assets[3].decimals = MAX_DECIMALS + 1;
+        assets[3].price = 0;
+        assets[3].ltv_bps = AssetInfo::FULL_LTV_BPS + 1;
+        assets[2].min_price = 2;
+        assets[1].fallback_oracle = Some(OracleSource::Chainlink {
+            feed: Pubkey::default(),
+            price_decimals: 6,
+        });
+        assets[3].min_price = 2;
+        assets[3].max_price = 1;
+        let registry = registry(
+            assets,
+            vec![param(0, 2, 50), param(2, 0, 50), param(0, 9, 101)],
+        );
+
+        assert_eq!(
+            registry_issues(&registry),
+            vec![
+                RegistryIssue::DuplicateAssetId { asset_id: 0 },
+                RegistryIssue::MissingOracleFeed { asset_id: 0 },
+                RegistryIssue::InvalidPriceBounds { asset_id: 2 },
+                RegistryIssue::DuplicateMint { asset_id: 3 },
+                RegistryIssue::InvalidDecimals { asset_id: 3 },
+                RegistryIssue::ZeroPrice { asset_id: 3 },
+                RegistryIssue::InvalidLtv { asset_id: 3 },
+                RegistryIssue::InvalidPriceBounds { asset_id: 3 },
+                RegistryIssue::DuplicateRiskParam {
+                    asset_id_a: 2,
+                    asset_id_b: 0
+                },
+                RegistryIssue::UnknownRiskParamAsset {
+                    asset_id_a: 0,
+                    asset_id_b: 9
+                },
+                RegistryIssue::RiskLevelOutOfRange {
+                    asset_id_a: 0,
+                    asset_id_b: 9
+                },
+            ]
+        );
+    }
+}
@@ -94,8 +94,22 @@ describe('chainlink-solana-demo', () => {
 
   // PDAs
   let assetRegistryPda: web3.PublicKey;
+  let obligationDirectoryPda: web3.PublicKey;
+  let marketStatsPda: web3.PublicKey;
+  let featureGatesPda: web3.PublicKey;
   let testObligationPda: web3.PublicKey;
 
+  // Directory page that the next obligation's owner will be appended to
+  const nextObligationPagePda = async () => {
+    const directory = await program.account.obligationDirectory.fetch(obligationDirectoryPda);
+    const page = new BN(directory.totalCreated).divn(32);
+    const [pagePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("obligation_page"), page.toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    return pagePda;
+  };
+
   // Helper function to extract and print logs
   const printTransactionLogs = async (txSig: string, testName: string) => {
     console.log(`\n${'='.repeat(60)}`);
@@ -148,6 +162,21 @@ describe('chainlink-solana-demo', () => {
       program.programId
     );
 
+    [obligationDirectoryPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("obligation_directory")],
+      program.programId
+    );
+
+    [marketStatsPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("market_stats")],
+      program.programId
+    );
+
+    [featureGatesPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("feature_gates")],
+      program.programId
+    );
+
     [testObligationPda] = web3.PublicKey.findProgramAddressSync(
       [Buffer.from('obligation'), testUser.publicKey.toBuffer()],
       program.programId
@@ -171,7 +200,11 @@ describe('chainlink-solana-demo', () => {
         .initializeAssetRegistry()
         .accounts({
           assetRegistry: assetRegistryPda,
+          obligationDirectory: obligationDirectoryPda,
+          marketStats: marketStatsPda,
+          featureGates: featureGatesPda,
           authority,
+          payer: authority,
           systemProgram: web3.SystemProgram.programId,
         })
         .rpc();
@@ -228,10 +261,19 @@ describe('chainlink-solana-demo', () => {
 
     it("initialize test obligation", async () => {
       await program.methods
-        .initObligation()
+        .initObligation({ cross: {} })
         .accounts({
           obligation: testObligationPda,
+          obligationDirectory: obligationDirectoryPda,
+          obligationPage: await nextObligationPagePda(),
+          featureGates: featureGatesPda,
+          assetRegistry: assetRegistryPda,
+          ownerObligations: web3.PublicKey.findProgramAddressSync(
+            [Buffer.from("owner_obligations"), testUser.publicKey.toBuffer()],
+            program.programId
+          )[0],
           owner: testUser.publicKey,
+          payer: testUser.publicKey,
           systemProgram: web3.SystemProgram.programId,
         })
         .signers([testUser])
@@ -252,6 +294,7 @@ describe('chainlink-solana-demo', () => {
         .accounts({
           obligation: testObligationPda,
           assetRegistry: assetRegistryPda,
+          marketStats: marketStatsPda,
           owner: testUser.publicKey,
         })
         .signers([testUser])
@@ -264,6 +307,7 @@ describe('chainlink-solana-demo', () => {
         .accounts({
           obligation: testObligationPda,
           assetRegistry: assetRegistryPda,
+          marketStats: marketStatsPda,
           owner: testUser.publicKey,
         })
         .signers([testUser])
@@ -273,10 +317,11 @@ describe('chainlink-solana-demo', () => {
       // Add borrows
       // Asset C: $250
       const tx1 = await program.methods
-        .addBorrow(ASSET_C, new BN(250000000))
+        .borrow(ASSET_C, new BN(250000000))
         .accounts({
           obligation: testObligationPda,
           assetRegistry: assetRegistryPda,
+          marketStats: marketStatsPda,
           owner: testUser.publicKey,
         })
         .signers([testUser])
@@ -287,10 +332,11 @@ describe('chainlink-solana-demo', () => {
 
       // Asset D: $750
       const txSig = await program.methods
-        .addBorrow(ASSET_D, new BN(750000000))
+        .borrow(ASSET_D, new BN(750000000))
         .accounts({
           obligation: testObligationPda,
           assetRegistry: assetRegistryPda,
+          marketStats: marketStatsPda,
           owner: testUser.publicKey,
         })
         .signers([testUser])
@@ -321,10 +367,11 @@ describe('chainlink-solana-demo', () => {
       
       try {
         const txSig = await program.methods
-        .addBorrow(ASSET_C, new BN(500000000))
+        .borrow(ASSET_C, new BN(500000000))
         .accounts({
           obligation: testObligationPda,
           assetRegistry: assetRegistryPda,
+          marketStats: marketStatsPda,
           owner: testUser.publicKey,
         })
         .signers([testUser])
@@ -336,5 +383,91 @@ describe('chainlink-solana-demo', () => {
       }
     });
   });
+
+  describe("Deprecated assets", () => {
+    const modifyAccounts = () => ({
+      obligation: testObligationPda,
+      assetRegistry: assetRegistryPda,
+      marketStats: marketStatsPda,
+      owner: testUser.publicKey,
+    });
+
+    it("deprecate asset D", async () => {
+      await program.methods
+        .setAssetStatus(ASSET_D, { deprecated: {} })
+        .accounts({
+          assetRegistry: assetRegistryPda,
+          authority,
+        })
+        .rpc();
+
+      const registry = await program.account.assetRegistry.fetch(assetRegistryPda);
+      const assetD = registry.assets.find(a => a.id === ASSET_D);
+      assert.deepEqual(assetD.status, { deprecated: {} });
+      console.log("✓ Asset D deprecated");
+    });
+
+    it("rejects new borrows of a deprecated asset", async () => {
+      try {
+        await program.methods
+          .borrow(ASSET_D, new BN(1000000))
+          .accounts(modifyAccounts())
+          .signers([testUser])
+          .rpc();
+        assert.fail("borrow should have been rejected");
+      } catch (error) {
+        assert.equal(error.error?.errorCode?.code, "AssetDeprecated");
+      }
+      console.log("✓ New borrow of Asset D rejected");
+    });
+
+    it("rejects new deposits of a deprecated asset", async () => {
+      try {
+        await program.methods
+          .addDeposit(ASSET_D, new BN(1000000))
+          .accounts(modifyAccounts())
+          .signers([testUser])
+          .rpc();
+        assert.fail("deposit should have been rejected");
+      } catch (error) {
+        assert.equal(error.error?.errorCode?.code, "AssetDeprecated");
+      }
+      console.log("✓ New deposit of Asset D rejected");
+    });
+
+    it("still allows repaying a deprecated asset", async () => {
+      await program.methods
+        .repay(ASSET_D, new BN(100000000))
+        .accounts(modifyAccounts())
+        .signers([testUser])
+        .rpc();
+
+      const obligation = await program.account.obligation.fetch(testObligationPda);
+      const borrowD = obligation.borrows.find(b => b.assetId === ASSET_D);
+      assert.equal(borrowD.amount.toNumber(), 650000000);
+      console.log("✓ Repaid $100 of deprecated Asset D");
+    });
+
+    it("reactivated asset accepts borrows again", async () => {
+      await program.methods
+        .setAssetStatus(ASSET_D, { active: {} })
+        .accounts({
+          assetRegistry: assetRegistryPda,
+          authority,
+        })
+        .rpc();
+
+      await program.methods
+        .borrow(ASSET_D, new BN(100000000))
+        .accounts(modifyAccounts())
+        .signers([testUser])
+        .rpc();
+
+      const obligation = await program.account.obligation.fetch(testObligationPda);
+      const borrowD = obligation.borrows.find(b => b.assetId === ASSET_D);
+      assert.equal(borrowD.amount.toNumber(), 750000000);
+      console.log("✓ Asset D reactivated and borrowed again");
+    });
+  });
 });
  